@@ -0,0 +1,25 @@
+use clap::Parser;
+use env_logger::Env;
+use std::path::PathBuf;
+
+#[path = "../mockd.rs"]
+mod mockd;
+
+#[derive(Parser)]
+/// A local stand-in for the rec API, for testing recfs offline
+struct Args {
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    /// Address to listen on
+    listen: String,
+
+    /// Directory storing the remote tree and file contents
+    root: PathBuf,
+}
+
+fn main() {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let cli = Args::parse();
+    let server = mockd::MockServer::start(&cli.listen, &cli.root).unwrap();
+    println!("API URL: {}", server.api_url());
+    server.wait();
+}
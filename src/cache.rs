@@ -1,44 +1,712 @@
 use std::{
-    collections::HashMap,
-    io::Write,
-    path::PathBuf,
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    os::unix::{fs::FileExt, io::AsRawFd},
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicUsize, Ordering},
-        Arc, Mutex,
+        Arc, Condvar, Mutex,
     },
 };
 
-use file_lock::FileOptions;
-use log::{info, warn};
+use log::{error, info, warn};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
 
+use crate::client::error::{RecError, RecResult};
+use crate::client::list::RecListItem;
+use crate::client::upload::UploadSession;
+use crate::client::RecClient;
 use crate::fid::Fid;
 
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    // remote files are downloaded in blocks of this size
+    pub block_size: u64,
+    // bytes fetched in the background after a sequential read
+    pub readahead: u64,
+    // files and bytes fetched in the background ahead of a folder traversal
+    pub prefetch_files: usize,
+    pub prefetch_size: u64,
+    // downloaded files are evicted when the cache grows over this size
+    pub max_size: u64,
+    // keep downloaded files across mounts
+    pub persistent: bool,
+    // connections a large download is split over
+    pub connections: usize,
+    // bytes below which a download is not split further
+    pub min_split: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            block_size: 1 << 20,
+            readahead: 8 << 20,
+            prefetch_files: 8,
+            prefetch_size: 256 << 20,
+            max_size: 1 << 30,
+            persistent: false,
+            connections: 4,
+            min_split: 8 << 20,
+        }
+    }
+}
+
+// FidMap of a persistent cache, saved across mounts (see FidMap::save())
+const METADATA: &str = "fidmap.json";
+// pinned Fids of a persistent cache, one per line
+const PINS: &str = "pins";
+// created files of a persistent cache which are not uploaded yet, so that they are
+// uploaded again after a crash (see RecFs::recover_uploads())
+const JOURNAL: &str = "uploads.json";
+// locked by the mount using a persistent cache, so that two mounts never share its files
+const LOCK: &str = "lock";
+
+type Partials = Arc<Mutex<HashMap<Fid, Arc<Partial>>>>;
+
 pub struct Cache {
     basepath: PathBuf,
+    config: CacheConfig,
     create_counter: AtomicUsize,
     create_mapping: Arc<Mutex<HashMap<Fid, (Fid, String)>>>,
+    // created files until they are uploaded or unlinked, saved as JOURNAL
+    journal: Arc<Mutex<BTreeMap<Fid, PendingUpload>>>,
+    // remote files whose local copy has been modified but not uploaded yet
+    dirty: Arc<Mutex<HashSet<Fid>>>,
+    // remote files which are only partly downloaded
+    partials: Partials,
+    lru: Arc<Mutex<Lru>>,
+    // remote version (see RecListItem::version()) of each complete downloaded file
+    // saved next to it as <fid>.version
+    versions: Arc<Mutex<HashMap<Fid, String>>>,
+    // files and folders to be kept downloaded (see RecFs::setxattr())
+    pinned: Arc<Mutex<HashSet<Fid>>>,
+    // local copies opened by file handles, with the number of them
+    held: Mutex<HashMap<Fid, usize>>,
+    // held local copies found stale by validate(), dropped once they are not held anymore
+    stale: Mutex<HashSet<Fid>>,
+    lock: Mutex<Option<File>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadState {
+    // not closed yet
+    Writing,
+    Queued,
+    Uploading,
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingUpload {
+    pub fid: Fid,
+    pub parent: Fid,
+    pub name: String,
+    pub state: UploadState,
+    // the upload begun on rec, if any
+    pub session: Option<UploadSession>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JournalEntry {
+    fid: String,
+    parent: String,
+    name: String,
+    path: PathBuf,
+    state: UploadState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    session: Option<UploadSession>,
+}
+
+// size and last use of downloaded files, complete or not
+#[derive(Default)]
+struct Lru {
+    tick: u64,
+    entries: HashMap<Fid, LruEntry>,
+}
+
+struct LruEntry {
+    size: u64,
+    used: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, fid: Fid, size: Option<u64>) {
+        self.tick += 1;
+        let used = self.tick;
+        match (self.entries.get_mut(&fid), size) {
+            (Some(entry), size) => {
+                entry.used = used;
+                entry.size = size.unwrap_or(entry.size);
+            }
+            (None, Some(size)) => {
+                self.entries.insert(fid, LruEntry { size, used });
+            }
+            (None, None) => {}
+        }
+    }
+}
+
+// a remote file downloaded block by block into <fid>.partial
+// it is renamed to <fid> once all blocks are there
+struct Partial {
+    fid: Fid,
+    version: String,
+    // md5 given by rec, checked once all blocks are there
+    hash: Option<String>,
+    size: u64,
+    block_size: u64,
+    path: PathBuf,
+    file: File,
+    blocks: Mutex<Blocks>,
+    // notified whenever a fetch of blocks ends, successfully or not
+    fetched: Condvar,
+    // where the present blocks are saved in a persistent cache, so that the next mount resumes
+    blocks_path: Option<PathBuf>,
+}
+
+// the download state of <fid>.partial, saved as <fid>.blocks
+#[derive(Serialize, Deserialize)]
+struct SavedBlocks {
+    version: String,
+    hash: Option<String>,
+    size: u64,
+    block_size: u64,
+    present: Vec<u64>,
+}
+
+struct Blocks {
+    present: Bitmap,
+    fetching: Bitmap,
+    missing: u64,
+}
+
+struct Bitmap(Vec<u64>);
+
+impl Bitmap {
+    fn new(len: u64) -> Self {
+        Bitmap(vec![0; len.div_ceil(64) as usize])
+    }
+
+    fn get(&self, i: u64) -> bool {
+        self.0[(i / 64) as usize] & (1 << (i % 64)) != 0
+    }
+
+    fn set(&mut self, i: u64, value: bool) {
+        if value {
+            self.0[(i / 64) as usize] |= 1 << (i % 64);
+        } else {
+            self.0[(i / 64) as usize] &= !(1 << (i % 64));
+        }
+    }
+}
+
+// writes a response body to a file at increasing offsets
+struct OffsetWriter<'a> {
+    file: &'a File,
+    pos: u64,
+}
+
+impl Write for OffsetWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write_all_at(buf, self.pos)?;
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Partial {
+    fn blocks(&self) -> u64 {
+        self.size.div_ceil(self.block_size)
+    }
+
+    // the blocks holding bytes offset..offset+len, None if the range is empty
+    fn block_range(&self, offset: u64, len: u64) -> Option<(u64, u64)> {
+        let end = offset.saturating_add(len).min(self.size);
+        if offset >= end {
+            return None;
+        }
+        Some((offset / self.block_size, (end - 1) / self.block_size))
+    }
+
+    fn is_complete(&self) -> bool {
+        self.blocks.lock().unwrap().missing == 0
+    }
+
+    // bytes of the file which are downloaded (approximately)
+    fn local_size(&self) -> u64 {
+        let missing = self.blocks.lock().unwrap().missing;
+        self.size
+            .saturating_sub(missing.saturating_mul(self.block_size))
+    }
+
+    // take over the first run of blocks in first..=last that nobody has or is fetching
+    fn claim(&self, first: u64, last: u64) -> Option<(u64, u64)> {
+        let mut blocks = self.blocks.lock().unwrap();
+        let free = |blocks: &Blocks, b: u64| !blocks.present.get(b) && !blocks.fetching.get(b);
+        let start = (first..=last).find(|&b| free(&blocks, b))?;
+        let end = (start..=last)
+            .take_while(|&b| free(&blocks, b))
+            .last()
+            .unwrap();
+        for b in start..=end {
+            blocks.fetching.set(b, true);
+        }
+        Some((start, end))
+    }
+
+    // make sure blocks first..=last are present, downloading or waiting for them
+    fn fetch(
+        &self,
+        client: &RecClient,
+        config: &CacheConfig,
+        first: u64,
+        last: u64,
+    ) -> RecResult<()> {
+        loop {
+            if let Some(run) = self.claim(first, last) {
+                self.download_split(client, config, run)?;
+                continue;
+            }
+            let mut blocks = self.blocks.lock().unwrap();
+            loop {
+                if (first..=last).all(|b| blocks.present.get(b)) {
+                    return Ok(());
+                }
+                // another fetch failed: try it ourselves
+                if (first..=last).any(|b| !blocks.present.get(b) && !blocks.fetching.get(b)) {
+                    break;
+                }
+                blocks = self.fetched.wait(blocks).unwrap();
+            }
+        }
+    }
+
+    // download a claimed run of blocks over several connections at once
+    // each of them gets a range of at least min_split bytes
+    fn download_split(
+        &self,
+        client: &RecClient,
+        config: &CacheConfig,
+        (first, last): (u64, u64),
+    ) -> RecResult<()> {
+        let bytes = ((last + 1) * self.block_size).min(self.size) - first * self.block_size;
+        let parts = (bytes / config.min_split.max(1)).clamp(1, config.connections.max(1) as u64);
+        if parts == 1 {
+            return self.download(client, (first, last));
+        }
+        let per = (last - first + 1).div_ceil(parts);
+        std::thread::scope(|s| {
+            let downloads: Vec<_> = (first..=last)
+                .step_by(per as usize)
+                .map(|start| {
+                    s.spawn(move || self.download(client, (start, (start + per - 1).min(last))))
+                })
+                .collect();
+            // every range is waited for, so that none is left fetching
+            let results: Vec<RecResult<()>> =
+                downloads.into_iter().map(|d| d.join().unwrap()).collect();
+            results.into_iter().collect()
+        })
+    }
+
+    // download a claimed run of blocks
+    fn download(&self, client: &RecClient, (first, last): (u64, u64)) -> RecResult<()> {
+        let start = first * self.block_size;
+        let end = ((last + 1) * self.block_size).min(self.size);
+        // what a failed try has written is kept: the next one asks for the rest only
+        let mut pos = start;
+        let res = client.with_retry(&format!("download {}", self.fid), true, || {
+            let mut resp = client.download_range(self.fid, pos, end - 1)?;
+            // the server ignored the range and sent the whole file
+            let whole = resp.status() == StatusCode::OK;
+            let mut writer = OffsetWriter {
+                file: &self.file,
+                pos: if whole { 0 } else { pos },
+            };
+            let copied = resp.copy_to(&mut writer);
+            // the whole file may break off after the blocks asked for, which are there then
+            if whole && writer.pos >= end {
+                return Ok(copied.is_ok() && writer.pos >= self.size);
+            }
+            pos = pos.max(writer.pos);
+            copied?;
+            let expected = if whole { self.size } else { end };
+            if writer.pos < expected {
+                return Err(RecError::Io(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "download of {} ended at byte {} instead of {}",
+                        self.fid, writer.pos, expected
+                    ),
+                )));
+            }
+            Ok(whole)
+        });
+
+        let mut blocks = self.blocks.lock().unwrap();
+        for b in first..=last {
+            blocks.fetching.set(b, false);
+        }
+        let fetched = match res {
+            Ok(true) => 0..self.blocks(),
+            Ok(false) => first..last + 1,
+            Err(e) => {
+                self.fetched.notify_all();
+                return Err(e);
+            }
+        };
+        for b in fetched {
+            if !blocks.present.get(b) {
+                blocks.present.set(b, true);
+                blocks.missing -= 1;
+            }
+        }
+        self.save_blocks(&blocks);
+        self.fetched.notify_all();
+        Ok(())
+    }
+
+    fn save_blocks(&self, blocks: &Blocks) {
+        let path = match &self.blocks_path {
+            Some(path) if blocks.missing > 0 => path,
+            _ => return,
+        };
+        let saved = SavedBlocks {
+            version: self.version.clone(),
+            hash: self.hash.clone(),
+            size: self.size,
+            block_size: self.block_size,
+            present: blocks.present.0.clone(),
+        };
+        let tmp = path.with_extension("blocks.tmp");
+        let res = serde_json::to_vec(&saved)
+            .map_err(std::io::Error::from)
+            .and_then(|data| std::fs::write(&tmp, data))
+            .and_then(|_| std::fs::rename(&tmp, path));
+        if let Err(e) = res {
+            warn!("Failed to save {}: {}", path.display(), e);
+        }
+    }
 }
 
 impl Default for Cache {
     fn default() -> Self {
-        let basepath = std::env::temp_dir().join("recfs").join(
+        Cache::new(Cache::temp_path(), CacheConfig::default()).unwrap()
+    }
+}
+
+impl Cache {
+    pub fn new(basepath: PathBuf, config: CacheConfig) -> anyhow::Result<Self> {
+        let mut lock = None;
+        if config.persistent {
+            info!("Cache folder: {}", basepath.display());
+            std::fs::create_dir_all(&basepath)?;
+            lock = Some(Cache::lock_path(&basepath)?);
+        } else {
+            Cache::init_path(&basepath);
+        }
+        let cache = Self {
+            basepath,
+            config,
+            create_counter: AtomicUsize::new(0),
+            create_mapping: Arc::new(Mutex::new(HashMap::new())),
+            journal: Arc::new(Mutex::new(BTreeMap::new())),
+            dirty: Arc::new(Mutex::new(HashSet::new())),
+            partials: Arc::new(Mutex::new(HashMap::new())),
+            lru: Arc::new(Mutex::new(Lru::default())),
+            versions: Arc::new(Mutex::new(HashMap::new())),
+            pinned: Arc::new(Mutex::new(HashSet::new())),
+            held: Mutex::new(HashMap::new()),
+            stale: Mutex::new(HashSet::new()),
+            lock: Mutex::new(lock),
+        };
+        if cache.config.persistent {
+            cache.load();
+        }
+        Ok(cache)
+    }
+
+    // an exclusive flock(), held until cleanup()
+    fn lock_path(basepath: &Path) -> anyhow::Result<File> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(basepath.join(LOCK))?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == std::io::ErrorKind::WouldBlock {
+                anyhow::bail!("{} is used by another recfs mount", basepath.display());
+            }
+            return Err(e.into());
+        }
+        Ok(file)
+    }
+
+    // pick up the files downloaded by previous mounts, their unfinished downloads, and created
+    // files in the journal
+    // anything else left is moved to lost+found: files which were not uploaded must neither be
+    // served as remote content nor overwritten
+    fn load(&self) {
+        let entries = match std::fs::read_dir(&self.basepath) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Failed to read {}: {}", self.basepath.display(), e);
+                return;
+            }
+        };
+        let mut journaled = self.load_journal();
+        let mut last_created = None;
+        let mut files = Vec::new();
+        let mut partial_files = Vec::new();
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name == "lost+found"
+                || name == LOCK
+                || name.starts_with(METADATA)
+                || name.starts_with(JOURNAL)
+            {
+                continue;
+            }
+            if name == PINS {
+                if let Ok(pins) = std::fs::read_to_string(entry.path()) {
+                    let mut pinned = self.pinned.lock().unwrap();
+                    pinned.extend(pins.lines().filter_map(|l| l.parse::<Fid>().ok()));
+                }
+                continue;
+            }
+            if let Some(fid) = name.strip_suffix(".partial") {
+                partial_files.push(fid.to_owned());
+                continue;
+            }
+            if let Some(fid) = name.strip_suffix(".blocks") {
+                if !self.basepath.join(format!("{}.partial", fid)).exists() {
+                    let _ = std::fs::remove_file(entry.path());
+                }
+                continue;
+            }
+            if let Some(fid) = name.strip_suffix(".version") {
+                if !self.basepath.join(fid).exists() {
+                    let _ = std::fs::remove_file(entry.path());
+                }
+                continue;
+            }
+            let version = std::fs::read_to_string(self.version_path(&name));
+            match (name.parse::<Fid>(), version) {
+                (Ok(fid), Ok(version)) if !fid.is_created() => {
+                    let metadata = entry.metadata().ok();
+                    let size = metadata.as_ref().map_or(0, |m| m.len());
+                    let used = metadata.and_then(|m| m.modified().ok());
+                    files.push((used, fid, size, version));
+                }
+                (Ok(fid), _) if journaled.contains_key(&fid) => {
+                    let id = name["write-".len()..].parse::<usize>().unwrap_or(0);
+                    last_created = last_created.max(Some(id));
+                    let pending = journaled.remove(&fid).unwrap();
+                    self.create_mapping
+                        .lock()
+                        .unwrap()
+                        .insert(fid, (pending.parent, pending.name.clone()));
+                    self.journal.lock().unwrap().insert(fid, pending);
+                }
+                _ => self.move_to_lost(&name),
+            }
+        }
+        for pending in journaled.values() {
+            warn!(
+                "Cache: {} ({}) is in {} but not found",
+                pending.name, pending.fid, JOURNAL
+            );
+        }
+        if !journaled.is_empty() {
+            self.save_journal(&self.journal.lock().unwrap());
+        }
+        // new files never take the place of the ones left
+        if let Some(id) = last_created {
+            self.create_counter.store(id + 1, Ordering::SeqCst);
+        }
+        // unfinished downloads are resumed if their state was saved
+        let mut resumed = 0;
+        for name in partial_files {
+            let restored = name.parse().ok().and_then(|fid| self.restore_partial(fid));
+            match restored {
+                Some(partial) => {
+                    resumed += 1;
+                    self.lru
+                        .lock()
+                        .unwrap()
+                        .touch(partial.fid, Some(partial.local_size()));
+                    self.partials.lock().unwrap().insert(partial.fid, partial);
+                }
+                None => {
+                    let _ = std::fs::remove_file(self.basepath.join(format!("{}.partial", name)));
+                    let _ = std::fs::remove_file(self.basepath.join(format!("{}.blocks", name)));
+                }
+            }
+        }
+        // the least recently modified ones are evicted first
+        files.sort_by_key(|f| f.0);
+        info!(
+            "Cache: reuse {} downloaded files, resume {} unfinished downloads",
+            files.len(),
+            resumed
+        );
+        let mut lru = self.lru.lock().unwrap();
+        let mut versions = self.versions.lock().unwrap();
+        for (_, fid, size, version) in files {
+            lru.touch(fid, Some(size));
+            versions.insert(fid, version);
+        }
+    }
+
+    // an unfinished download left by a previous mount, with the blocks it had got
+    fn restore_partial(&self, fid: Fid) -> Option<Arc<Partial>> {
+        let path = self.basepath.join(format!("{}.partial", fid));
+        let blocks_path = self.basepath.join(format!("{}.blocks", fid));
+        let saved: SavedBlocks = serde_json::from_slice(&std::fs::read(&blocks_path).ok()?).ok()?;
+        let blocks = saved.size.div_ceil(saved.block_size);
+        if saved.block_size != self.config.block_size
+            || saved.present.len() != Bitmap::new(blocks).0.len()
+        {
+            return None;
+        }
+        let file = OpenOptions::new().read(true).write(true).open(&path).ok()?;
+        if file.metadata().ok()?.len() != saved.size {
+            return None;
+        }
+        let present = Bitmap(saved.present);
+        let missing = (0..blocks).filter(|b| !present.get(*b)).count() as u64;
+        Some(Arc::new(Partial {
+            fid,
+            version: saved.version,
+            hash: saved.hash,
+            size: saved.size,
+            block_size: saved.block_size,
+            path,
+            file,
+            blocks: Mutex::new(Blocks {
+                present,
+                fetching: Bitmap::new(blocks),
+                missing,
+            }),
+            fetched: Condvar::new(),
+            blocks_path: Some(blocks_path),
+        }))
+    }
+
+    fn load_journal(&self) -> BTreeMap<Fid, PendingUpload> {
+        let path = self.basepath.join(JOURNAL);
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(_) => return BTreeMap::new(),
+        };
+        let entries: Vec<JournalEntry> = match serde_json::from_slice(&data) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Failed to parse {}: {}", path.display(), e);
+                return BTreeMap::new();
+            }
+        };
+        entries
+            .into_iter()
+            .filter_map(|e| {
+                let fid = e.fid.parse::<Fid>().ok().filter(|f| f.is_created())?;
+                let pending = PendingUpload {
+                    fid,
+                    parent: e.parent.parse().ok()?,
+                    name: e.name,
+                    state: e.state,
+                    session: e.session,
+                };
+                Some((fid, pending))
+            })
+            .collect()
+    }
+
+    fn save_journal(&self, journal: &BTreeMap<Fid, PendingUpload>) {
+        if !self.config.persistent {
+            return;
+        }
+        let entries: Vec<JournalEntry> = journal
+            .values()
+            .map(|p| JournalEntry {
+                fid: p.fid.to_string(),
+                parent: p.parent.to_string(),
+                name: p.name.clone(),
+                path: self.get_created_path(p.fid),
+                state: p.state,
+                session: p.session.clone(),
+            })
+            .collect();
+        let path = self.basepath.join(JOURNAL);
+        let tmp = path.with_extension("json.tmp");
+        let res = serde_json::to_vec(&entries)
+            .map_err(std::io::Error::from)
+            .and_then(|data| std::fs::write(&tmp, data))
+            .and_then(|_| std::fs::rename(&tmp, &path));
+        if let Err(e) = res {
+            warn!("Failed to save {}: {}", path.display(), e);
+        }
+    }
+
+    // lost+found of a persistent cache is in it, temporary ones share the one next to them
+    fn lost_path(&self) -> PathBuf {
+        match self.basepath.parent() {
+            Some(parent) if !self.config.persistent => parent.join("lost+found"),
+            _ => self.basepath.join("lost+found"),
+        }
+    }
+
+    // keep a file which was not uploaded in lost+found, without overwriting older ones
+    fn move_to_lost(&self, name: &str) {
+        let lost = self.lost_path();
+        let mut target = lost.join(name);
+        let mut n = 0;
+        while target.exists() {
+            n += 1;
+            target = lost.join(format!("{}.{}", name, n));
+        }
+        warn!("Cache: move {} to {}", name, target.display());
+        let res = std::fs::create_dir_all(&lost)
+            .and_then(|_| std::fs::rename(self.basepath.join(name), &target));
+        if let Err(e) = res {
+            warn!("Failed to move {}: {}", name, e);
+        }
+    }
+
+    fn version_path(&self, fid: &str) -> PathBuf {
+        self.basepath.join(format!("{}.version", fid))
+    }
+
+    // a new random folder under /tmp/recfs
+    pub fn temp_path() -> PathBuf {
+        std::env::temp_dir().join("recfs").join(
             thread_rng()
                 .sample_iter(&Alphanumeric)
                 .take(10)
                 .map(char::from)
                 .collect::<String>(),
-        );
-        Cache::init_path(&basepath);
-        Self {
-            basepath,
-            create_counter: AtomicUsize::new(0),
-            create_mapping: Arc::new(Mutex::new(HashMap::new())),
-        }
+        )
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    // where FidMap is saved across mounts, if the cache is kept too
+    pub fn metadata_path(&self) -> Option<PathBuf> {
+        self.config.persistent.then(|| self.basepath.join(METADATA))
     }
-}
 
-impl Cache {
     fn init_path(path: &PathBuf) {
         info!("Cache folder: {}", path.display());
         if !path.exists() {
@@ -69,22 +737,340 @@ impl Cache {
         }
     }
 
-    pub fn fetch(&self, fid: Fid, url: String) -> anyhow::Result<()> {
-        let download_path = self.basepath.join(format!("{}.{}", fid, "download"));
+    // drop the local copy of a remote file if it is not the version listed anymore
+    // a copy held by file handles is dropped when the last of them is closed, so that they
+    // never read or write files which are gone
+    pub fn validate(&self, item: &RecListItem) {
+        let fid = item.fid;
+        if self.is_dirty(fid) {
+            return;
+        }
+        let version = item.version();
+        let stale = self
+            .partials
+            .lock()
+            .unwrap()
+            .get(&fid)
+            .is_some_and(|p| p.version != version)
+            || self
+                .versions
+                .lock()
+                .unwrap()
+                .get(&fid)
+                .is_some_and(|v| *v != version);
+        if !stale {
+            return;
+        }
+        if self.held.lock().unwrap().contains_key(&fid) {
+            info!(
+                "Cache: {} has changed on rec, drop the local copy once closed",
+                fid
+            );
+            self.stale.lock().unwrap().insert(fid);
+            return;
+        }
+        info!("Cache: {} has changed on rec, drop the local copy", fid);
+        self.drop_copy(fid);
+    }
+
+    fn drop_copy(&self, fid: Fid) {
+        self.partials.lock().unwrap().remove(&fid);
+        self.versions.lock().unwrap().remove(&fid);
+        self.lru.lock().unwrap().entries.remove(&fid);
+        self.remove_files(fid);
+    }
+
+    // the local copy of fid is opened by a file handle
+    pub fn hold(&self, fid: Fid) {
+        *self.held.lock().unwrap().entry(fid).or_default() += 1;
+    }
+
+    // a file handle holding the local copy of fid is closed
+    // a copy found stale meanwhile is dropped with the last of them
+    pub fn unhold(&self, fid: Fid) {
+        let mut held = self.held.lock().unwrap();
+        match held.get_mut(&fid) {
+            Some(count) if *count > 1 => *count -= 1,
+            Some(_) => {
+                held.remove(&fid);
+                drop(held);
+                if self.stale.lock().unwrap().remove(&fid) && !self.is_dirty(fid) {
+                    info!("Cache: drop the stale local copy of {}", fid);
+                    self.drop_copy(fid);
+                }
+            }
+            None => {}
+        }
+    }
+
+    // download the whole file
+    pub fn fetch(&self, client: &RecClient, item: &RecListItem) -> RecResult<()> {
+        self.fetch_range(client, item, 0, item.bytes as u64)
+    }
+
+    // download the blocks holding bytes offset..offset+len of a remote file
+    pub fn fetch_range(
+        &self,
+        client: &RecClient,
+        item: &RecListItem,
+        offset: u64,
+        len: u64,
+    ) -> RecResult<()> {
+        let fid = item.fid;
+        let partial = match self.partial(item)? {
+            Some(partial) => partial,
+            None => {
+                self.lru.lock().unwrap().touch(fid, Some(item.bytes as u64));
+                return Ok(());
+            }
+        };
+        let res = match partial.block_range(offset, len) {
+            Some((first, last)) => partial.fetch(client, &self.config, first, last),
+            None => Ok(()),
+        };
+        self.lru
+            .lock()
+            .unwrap()
+            .touch(fid, Some(partial.local_size()));
+        res?;
+        finish(&self.partials, &self.versions, &self.basepath, &partial)?;
+        Ok(())
+    }
+
+    // whether bytes offset..offset+len of fid are local, so that reading them needs no request
+    pub fn has_range(&self, fid: Fid, offset: u64, len: u64) -> bool {
+        if self.contains(fid).is_some() {
+            return true;
+        }
+        let partial = match self.partials.lock().unwrap().get(&fid) {
+            Some(partial) => partial.clone(),
+            None => return false,
+        };
+        match partial.block_range(offset, len) {
+            Some((first, last)) => {
+                let blocks = partial.blocks.lock().unwrap();
+                (first..=last).all(|b| blocks.present.get(b))
+            }
+            None => true,
+        }
+    }
+
+    pub fn pin(&self, fid: Fid, pinned: bool) {
+        let mut pins = self.pinned.lock().unwrap();
+        let changed = if pinned {
+            pins.insert(fid)
+        } else {
+            pins.remove(&fid)
+        };
+        if changed && self.config.persistent {
+            let content: String = pins.iter().map(|fid| format!("{}\n", fid)).collect();
+            if let Err(e) = std::fs::write(self.basepath.join(PINS), content) {
+                warn!("Failed to save pinned files: {}", e);
+            }
+        }
+    }
+
+    pub fn is_pinned(&self, fid: Fid) -> bool {
+        self.pinned.lock().unwrap().contains(&fid)
+    }
+
+    pub fn pinned(&self) -> Vec<Fid> {
+        self.pinned.lock().unwrap().iter().cloned().collect()
+    }
+
+    // record a read of a local copy
+    pub fn touch(&self, fid: Fid) {
+        self.lru.lock().unwrap().touch(fid, None);
+    }
+
+    // remove the least recently used downloaded files until the cache fits in max_size
+    // modified files, and files for which keep() is true (e.g. open or pinned ones), are kept
+    pub fn evict(&self, keep: impl Fn(Fid) -> bool) {
+        let mut lru = self.lru.lock().unwrap();
+        let mut total: u64 = lru.entries.values().map(|e| e.size).sum();
+        if total <= self.config.max_size {
+            return;
+        }
+        let mut candidates: Vec<(u64, Fid)> =
+            lru.entries.iter().map(|(fid, e)| (e.used, *fid)).collect();
+        candidates.sort();
+        for (_, fid) in candidates {
+            if total <= self.config.max_size {
+                break;
+            }
+            if self.is_dirty(fid) || keep(fid) {
+                continue;
+            }
+            info!("Cache: evict {}", fid);
+            self.partials.lock().unwrap().remove(&fid);
+            self.versions.lock().unwrap().remove(&fid);
+            self.remove_files(fid);
+            total -= lru.entries.remove(&fid).map_or(0, |e| e.size);
+        }
+    }
+
+    // remove everything but modified and created files which are not uploaded yet, which
+    // are moved to lost+found
+    // a persistent cache keeps the complete downloaded files too, and the others for the next
+    // mount
+    pub fn cleanup(&self) {
+        // unfinished downloads are resumed by the next mount
+        if self.config.persistent {
+            self.partials.lock().unwrap().clear();
+            // the folder may be used by another mount now
+            self.lock.lock().unwrap().take();
+            return;
+        }
+        self.partials.lock().unwrap().clear();
+        self.lru.lock().unwrap().entries.clear();
+        let entries = match std::fs::read_dir(&self.basepath) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Failed to clean up {}: {}", self.basepath.display(), e);
+                return;
+            }
+        };
+        let mut kept = 0;
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let keep = name
+                .to_str()
+                .and_then(|name| name.parse::<Fid>().ok())
+                .is_some_and(|fid| {
+                    self.is_dirty(fid)
+                        || self.create_mapping.lock().unwrap().contains_key(&fid)
+                        || self.journal.lock().unwrap().contains_key(&fid)
+                });
+            if keep {
+                kept += 1;
+                self.move_to_lost(&name.to_string_lossy());
+            } else if let Err(e) = std::fs::remove_file(entry.path()) {
+                warn!("Failed to remove {}: {}", entry.path().display(), e);
+            }
+        }
+        if kept > 0 {
+            warn!(
+                "Kept {} files which are not uploaded in {}",
+                kept,
+                self.lost_path().display()
+            );
+        }
+        if let Err(e) = std::fs::remove_dir(&self.basepath) {
+            warn!("Failed to remove {}: {}", self.basepath.display(), e);
+        }
+    }
+
+    fn remove_files(&self, fid: Fid) {
+        for path in [
+            self.basepath.join(fid.to_string()),
+            self.basepath.join(format!("{}.partial", fid)),
+            self.basepath.join(format!("{}.blocks", fid)),
+            self.version_path(&fid.to_string()),
+        ] {
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    warn!("Failed to remove {}: {}", path.display(), e);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // start downloading bytes offset..offset+len in the background
+    pub fn prefetch(&self, client: Arc<RecClient>, item: &RecListItem, offset: u64, len: u64) {
+        let partial = match self.partial(item) {
+            Ok(Some(partial)) => partial,
+            _ => return,
+        };
+        let run = match partial
+            .block_range(offset, len)
+            .and_then(|(first, last)| partial.claim(first, last))
+        {
+            Some(run) => run,
+            None => return,
+        };
+        let partials = self.partials.clone();
+        let versions = self.versions.clone();
+        let basepath = self.basepath.clone();
+        let lru = self.lru.clone();
+        let config = self.config.clone();
+        std::thread::spawn(move || {
+            let res = partial.download_split(&client, &config, run);
+            // it may have been evicted meanwhile
+            let cached = partials
+                .lock()
+                .unwrap()
+                .get(&partial.fid)
+                .is_some_and(|p| Arc::ptr_eq(p, &partial));
+            if cached {
+                lru.lock()
+                    .unwrap()
+                    .touch(partial.fid, Some(partial.local_size()));
+            }
+            let res = res.and_then(|_| finish(&partials, &versions, &basepath, &partial));
+            if let Err(e) = res {
+                warn!("Readahead of {} failed: {}", partial.fid, e);
+            }
+        });
+    }
+
+    // the local copy of fid, complete or not
+    // only bytes made sure by fetch_range() may be read from a partial copy
+    pub fn open(&self, fid: Fid) -> std::io::Result<File> {
+        let partials = self.partials.lock().unwrap();
+        match partials.get(&fid) {
+            Some(partial) => partial.file.try_clone(),
+            None => File::open(self.basepath.join(fid.to_string())),
+        }
+    }
+
+    // the download state of fid, or None if it is complete already
+    fn partial(&self, item: &RecListItem) -> RecResult<Option<Arc<Partial>>> {
+        let (fid, size) = (item.fid, item.bytes as u64);
+        let mut partials = self.partials.lock().unwrap();
+        if let Some(partial) = partials.get(&fid) {
+            return Ok(Some(partial.clone()));
+        }
         let final_path = self.basepath.join(fid.to_string());
-        let mut download_lock = file_lock::FileLock::lock(
-            download_path.clone(),
-            true,
-            FileOptions::new().write(true).create(true),
-        )?;
         if final_path.exists() {
-            return Ok(());
+            return Ok(None);
         }
-        let mut resp = reqwest::blocking::get(url)?;
-        std::io::copy(&mut resp, &mut download_lock.file)?;
-        // rename
-        std::fs::rename(download_path, final_path)?;
-        Ok(())
+        if size == 0 {
+            std::fs::write(self.version_path(&fid.to_string()), item.version())?;
+            File::create(final_path)?;
+            self.versions.lock().unwrap().insert(fid, item.version());
+            return Ok(None);
+        }
+        let path = self.basepath.join(format!("{}.partial", fid));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        file.set_len(size)?;
+        let blocks = size.div_ceil(self.config.block_size);
+        let partial = Arc::new(Partial {
+            fid,
+            version: item.version(),
+            hash: item.hash.clone().filter(|h| !h.is_empty()),
+            size,
+            block_size: self.config.block_size,
+            path,
+            file,
+            blocks: Mutex::new(Blocks {
+                present: Bitmap::new(blocks),
+                fetching: Bitmap::new(blocks),
+                missing: blocks,
+            }),
+            fetched: Condvar::new(),
+            blocks_path: self
+                .config
+                .persistent
+                .then(|| self.basepath.join(format!("{}.blocks", fid))),
+        });
+        partials.insert(fid, partial.clone());
+        Ok(Some(partial))
     }
 
     pub fn create(&self, parent: Fid, name: String) -> anyhow::Result<Fid> {
@@ -97,16 +1083,238 @@ impl Cache {
         self.create_mapping
             .lock()
             .unwrap()
-            .insert(fid, (parent, name));
+            .insert(fid, (parent, name.clone()));
+        let mut journal = self.journal.lock().unwrap();
+        journal.insert(
+            fid,
+            PendingUpload {
+                fid,
+                parent,
+                name,
+                state: UploadState::Writing,
+                session: None,
+            },
+        );
+        self.save_journal(&journal);
         Ok(fid)
     }
 
-    pub fn pop_created_info(&self, fid: Fid) -> Option<(Fid, String)> {
-        self.create_mapping.lock().unwrap().remove(&fid)
+    // parent and name of a created file, until it is uploaded or unlinked
+    pub fn created_info(&self, fid: Fid) -> Option<(Fid, String)> {
+        self.create_mapping.lock().unwrap().get(&fid).cloned()
+    }
+
+    // created files which are not uploaded yet, including the ones left by previous mounts
+    pub fn pending_uploads(&self) -> Vec<PendingUpload> {
+        self.journal.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn set_upload_state(&self, fid: Fid, state: UploadState) {
+        let mut journal = self.journal.lock().unwrap();
+        match journal.get_mut(&fid) {
+            Some(pending) if pending.state != state => pending.state = state,
+            _ => return,
+        }
+        self.save_journal(&journal);
+    }
+
+    pub fn upload_session(&self, fid: Fid) -> Option<UploadSession> {
+        let journal = self.journal.lock().unwrap();
+        journal.get(&fid).and_then(|p| p.session.clone())
+    }
+
+    pub fn set_upload_session(&self, fid: Fid, session: &UploadSession) {
+        let mut journal = self.journal.lock().unwrap();
+        if let Some(pending) = journal.get_mut(&fid) {
+            pending.session = Some(session.clone());
+            self.save_journal(&journal);
+        }
+    }
+
+    // the upload of a created file is to begin from scratch
+    pub fn forget_upload_session(&self, fid: Fid) {
+        let mut journal = self.journal.lock().unwrap();
+        if let Some(pending) = journal.get_mut(&fid) {
+            if pending.session.take().is_some() {
+                self.save_journal(&journal);
+            }
+        }
+    }
+
+    // a created file is uploaded or unlinked: its local copy is not needed anymore
+    pub fn forget_created(&self, fid: Fid) {
+        self.unjournal(fid);
+        self.remove_files(fid);
+    }
+
+    // a created file is uploaded: its local copy becomes the cached copy of the file on rec
+    pub fn uploaded(&self, fid: Fid, item: &RecListItem) {
+        if let Err(e) = self.replace(fid, item) {
+            warn!("Failed to keep local copy of {}: {}", item.name, e);
+            self.remove_files(fid);
+        }
+        self.unjournal(fid);
+    }
+
+    // a created file left by a previous mount which is not to be uploaded
+    pub fn discard_created(&self, fid: Fid) {
+        self.unjournal(fid);
+        self.move_to_lost(&fid.to_string());
+    }
+
+    // never upload a created file, whose local copy is still used (e.g. unlinked while open)
+    pub fn unjournal(&self, fid: Fid) {
+        self.create_mapping.lock().unwrap().remove(&fid);
+        let mut journal = self.journal.lock().unwrap();
+        if journal.remove(&fid).is_some() {
+            self.save_journal(&journal);
+        }
     }
 
     pub fn get_created_path(&self, fid: Fid) -> PathBuf {
         assert!(fid.is_created());
         self.basepath.join(fid.to_string())
     }
+
+    pub fn mark_dirty(&self, fid: Fid) {
+        assert!(!fid.is_created());
+        if self.dirty.lock().unwrap().insert(fid) {
+            // the local copy is not any version on rec anymore
+            self.versions.lock().unwrap().remove(&fid);
+            let _ = std::fs::remove_file(self.version_path(&fid.to_string()));
+        }
+    }
+
+    // the local copy of a remote file is emptied (e.g. opened with O_TRUNC): nothing is downloaded
+    pub fn truncate(&self, fid: Fid) -> std::io::Result<String> {
+        self.partials.lock().unwrap().remove(&fid);
+        for path in [
+            self.basepath.join(format!("{}.partial", fid)),
+            self.basepath.join(format!("{}.blocks", fid)),
+        ] {
+            let _ = std::fs::remove_file(path);
+        }
+        // in place, as other handles may have it open
+        let path = self.basepath.join(fid.to_string());
+        File::create(&path)?;
+        self.mark_dirty(fid);
+        self.lru.lock().unwrap().touch(fid, Some(0));
+        Ok(path.to_string_lossy().into_owned())
+    }
+
+    // the changes to the local copy of a remote file are given up: it is downloaded again
+    pub fn discard_dirty(&self, fid: Fid) {
+        if self.dirty.lock().unwrap().remove(&fid) {
+            self.lru.lock().unwrap().entries.remove(&fid);
+            self.remove_files(fid);
+        }
+    }
+
+    pub fn is_dirty(&self, fid: Fid) -> bool {
+        self.dirty.lock().unwrap().contains(&fid)
+    }
+
+    // the local copy of old has been uploaded as new: keep it as the clean cache of new
+    pub fn replace(&self, old: Fid, new: &RecListItem) -> std::io::Result<()> {
+        let new_fid = new.fid;
+        std::fs::write(self.version_path(&new_fid.to_string()), new.version())?;
+        std::fs::rename(
+            self.basepath.join(old.to_string()),
+            self.basepath.join(new_fid.to_string()),
+        )?;
+        self.dirty.lock().unwrap().remove(&old);
+        // the handles holding it go on with new
+        let mut held = self.held.lock().unwrap();
+        if let Some(count) = held.remove(&old) {
+            held.insert(new_fid, count);
+        }
+        drop(held);
+        if self.is_pinned(old) {
+            self.pin(old, false);
+            self.pin(new_fid, true);
+        }
+        self.versions.lock().unwrap().insert(new_fid, new.version());
+        let mut lru = self.lru.lock().unwrap();
+        lru.entries.remove(&old);
+        let size = std::fs::metadata(self.basepath.join(new_fid.to_string()))?.len();
+        lru.touch(new_fid, Some(size));
+        Ok(())
+    }
+}
+
+// move a completely downloaded file to its final place
+// a file which does not match its hash is dropped, to be downloaded again by the next read
+fn finish(
+    partials: &Mutex<HashMap<Fid, Arc<Partial>>>,
+    versions: &Mutex<HashMap<Fid, String>>,
+    basepath: &std::path::Path,
+    partial: &Arc<Partial>,
+) -> RecResult<()> {
+    if !partial.is_complete() {
+        return Ok(());
+    }
+    let checked = check_hash(&partial.path, partial.hash.as_deref());
+    let mut partials = partials.lock().unwrap();
+    if partials
+        .get(&partial.fid)
+        .is_some_and(|p| Arc::ptr_eq(p, partial))
+    {
+        if let Err(e) = checked {
+            error!("Cache: download of {} is corrupted: {}", partial.fid, e);
+            partials.remove(&partial.fid);
+            let _ = std::fs::remove_file(&partial.path);
+            if let Some(blocks_path) = &partial.blocks_path {
+                let _ = std::fs::remove_file(blocks_path);
+            }
+            return Err(e);
+        }
+        info!("Cache: {} is completely downloaded", partial.fid);
+        // the version goes first: a file without one is not trusted by later mounts
+        std::fs::write(
+            basepath.join(format!("{}.version", partial.fid)),
+            &partial.version,
+        )?;
+        std::fs::rename(&partial.path, basepath.join(partial.fid.to_string()))?;
+        if let Some(blocks_path) = &partial.blocks_path {
+            let _ = std::fs::remove_file(blocks_path);
+        }
+        partials.remove(&partial.fid);
+        versions
+            .lock()
+            .unwrap()
+            .insert(partial.fid, partial.version.clone());
+    }
+    Ok(())
+}
+
+// compare the content of a file with the hash given by rec, if any
+pub fn check_hash(path: &Path, expected: Option<&str>) -> RecResult<()> {
+    let expected = match expected {
+        Some(expected) if !expected.is_empty() => expected,
+        _ => return Ok(()),
+    };
+    let actual = file_md5(path)?;
+    if actual.eq_ignore_ascii_case(expected) {
+        Ok(())
+    } else {
+        Err(RecError::HashMismatch {
+            expected: expected.to_owned(),
+            actual,
+        })
+    }
+}
+
+// the md5 digest of a file in hex, as rec gives in listings
+pub fn file_md5(path: &Path) -> std::io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut context = md5::Context::new();
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        context.consume(&buf[..n]);
+    }
+    Ok(format!("{:x}", context.compute()))
 }
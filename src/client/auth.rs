@@ -6,9 +6,8 @@ use rpassword::read_password;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use crate::status_check;
-
-use super::{RecClient, AESKEY, CLIENTID, SIGNATURE};
+use super::error::{RecError, RecResult};
+use super::RecClient;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Token {
@@ -56,22 +55,26 @@ pub enum RecAuthMethod {
 }
 
 impl RecAuth {
-    pub fn get_tempticket(client: &RecClient) -> anyhow::Result<String> {
-        let body = client.get_noretry::<_, RecTempTicketEntity>(
-            "client/tempticket",
-            false,
-            &[("clientid", CLIENTID)],
-        )?;
-        status_check!(body);
-        Ok(body.entity.tempticket)
+    pub fn get_tempticket(client: &RecClient) -> RecResult<String> {
+        let entity = client
+            .get_without_refresh::<_, RecTempTicketEntity>(
+                "client/tempticket",
+                false,
+                &[("clientid", client.config().client_id.as_str())],
+            )?
+            .into_entity()?;
+        Ok(entity.tempticket)
     }
 
-    fn aes_encrypt(data: &str) -> anyhow::Result<String> {
-        let cipher = Cipher::new_128(AESKEY);
-        let mut iv = *AESKEY;
+    fn aes_encrypt(key: &[u8; 16], data: &str) -> RecResult<String> {
+        let cipher = Cipher::new_128(key);
+        let mut iv = *key;
         iv.reverse();
 
-        let data_len: u32 = data.len().try_into()?;
+        let data_len: u32 = data
+            .len()
+            .try_into()
+            .map_err(|_| RecError::Auth("Login message is too long".to_owned()))?;
         let mut payload = Vec::new();
         payload.extend_from_slice(&data_len.to_be_bytes());
         payload.extend_from_slice(data.as_bytes());
@@ -79,20 +82,20 @@ impl RecAuth {
         Ok(base64::encode(encrypted))
     }
 
-    fn aes_decrypt(data: &str, strip: bool) -> anyhow::Result<String> {
-        let cipher = Cipher::new_128(AESKEY);
-        let mut iv = *AESKEY;
+    fn aes_decrypt(key: &[u8; 16], data: &str, strip: bool) -> RecResult<String> {
+        let cipher = Cipher::new_128(key);
+        let mut iv = *key;
         iv.reverse();
 
-        let encrypted = base64::decode(data)?;
+        let encrypted = base64::decode(data).map_err(|e| RecError::Invalid(e.to_string()))?;
         let decrypted = cipher.cbc_decrypt(&iv, &encrypted);
-        if strip {
+        let decrypted = if strip {
             info!("{:?}", std::str::from_utf8(&decrypted));
-            let data = String::from_utf8(decrypted[16..].to_vec())?;
-            Ok(data)
+            decrypted.get(16..).unwrap_or_default().to_vec()
         } else {
-            Ok(String::from_utf8(decrypted)?)
-        }
+            decrypted
+        };
+        String::from_utf8(decrypted).map_err(|e| RecError::Invalid(e.to_string()))
     }
 
     fn serialize_dict(dict: &[(String, String)]) -> String {
@@ -113,7 +116,7 @@ impl RecAuth {
         client: &RecClient,
         cas_username: String,
         cas_password: String,
-    ) -> anyhow::Result<()> {
+    ) -> RecResult<()> {
         let tempticket = RecAuth::get_tempticket(client)?;
 
         let string = format!(
@@ -127,10 +130,11 @@ impl RecAuth {
                 "type": "nusoap"
             })
         );
-        let encrypted_string = RecAuth::aes_encrypt(&string)?;
+        let config = client.config();
+        let encrypted_string = RecAuth::aes_encrypt(&config.aes_key, &string)?;
         let sign = format!(
             "{}{}",
-            SIGNATURE,
+            config.signature,
             RecAuth::serialize_dict(&[
                 ("tempticket".to_string(), tempticket.clone()),
                 ("msg_encrypt".to_string(), encrypted_string.clone())
@@ -138,14 +142,15 @@ impl RecAuth {
         );
         let md5sign = format!("{:X}", md5::compute(sign));
 
-        let response = client.post_noretry::<_, RecEncryptedEntity>(
-            format!("user/login?tempticket={}&sign={}", tempticket, md5sign).as_str(),
-            false,
-            &json!({ "msg_encrypt": encrypted_string }),
-            None,
-        )?;
-        status_check!(response);
-        let decrypted_string = RecAuth::aes_decrypt(&response.entity.msg_encrypt, true)?;
+        let entity = client
+            .post_without_refresh::<_, RecEncryptedEntity>(
+                format!("user/login?tempticket={}&sign={}", tempticket, md5sign).as_str(),
+                false,
+                &json!({ "msg_encrypt": encrypted_string }),
+                None,
+            )?
+            .into_entity()?;
+        let decrypted_string = RecAuth::aes_decrypt(&config.aes_key, &entity.msg_encrypt, true)?;
         let userauth = serde_json::from_str::<RecUserAuthResponse>(&decrypted_string)?;
         info!("{:?}", userauth);
 
@@ -183,9 +188,10 @@ impl RecAuth {
         (auth_token.to_owned().to_string(), refresh_token.to_owned())
     }
 
-    pub fn interactive() -> RecAuthMethod {
+    pub fn interactive(client: &RecClient) -> RecAuthMethod {
         println!(
-            "By default, your CAS username and password will be sent to https://recapi.ustc.edu.cn"
+            "By default, your CAS username and password will be sent to {}",
+            client.config().api_url
         );
         println!("You can login in browser manually and paste the output of `document.cookie` in Developer Console instead");
         let mut username = String::new();
@@ -203,7 +209,7 @@ impl RecAuth {
         }
     }
 
-    pub fn try_keyring(&mut self) -> anyhow::Result<()> {
+    pub fn try_keyring(&mut self) -> RecResult<()> {
         let entry = keyring::Entry::new(SERVICENAME, "userauth");
         let userauth_json = entry.get_password()?;
         let userauth = serde_json::from_str::<Token>(&userauth_json)?;
@@ -211,34 +217,36 @@ impl RecAuth {
         Ok(())
     }
 
-    pub fn set_keyring(&mut self) -> anyhow::Result<()> {
+    pub fn set_keyring(&mut self) -> RecResult<()> {
         let entry = keyring::Entry::new(SERVICENAME, "userauth");
         let userauth_json = serde_json::to_string(&self.token.as_ref().unwrap())?;
         entry.set_password(&userauth_json)?;
         Ok(())
     }
 
-    pub fn clear_keyring(&self) -> anyhow::Result<()> {
+    pub fn clear_keyring(&self) -> RecResult<()> {
         let entry = keyring::Entry::new(SERVICENAME, "userauth");
         entry.delete_password()?;
         Ok(())
     }
 
-    pub fn refresh(&mut self, client: &RecClient) -> anyhow::Result<()> {
-        let resp = client.post_noretry::<_, RecEncryptedEntity>(
-            "user/refresh/token",
-            false,
-            &json!({
-                "clientid": CLIENTID,
-                "refresh_token": self.token.as_ref().unwrap().refresh_token
-            }),
-            Some(&[(
-                "X-auth-token".to_owned(),
-                self.token.as_ref().unwrap().access_token.to_owned(),
-            )]),
-        )?;
-        status_check!(resp);
-        let decrypted_string = RecAuth::aes_decrypt(&resp.entity.msg_encrypt, false)?;
+    pub fn refresh(&mut self, client: &RecClient) -> RecResult<()> {
+        let entity = client
+            .post_without_refresh::<_, RecEncryptedEntity>(
+                "user/refresh/token",
+                false,
+                &json!({
+                    "clientid": client.config().client_id,
+                    "refresh_token": self.token.as_ref().unwrap().refresh_token
+                }),
+                Some(&[(
+                    "X-auth-token".to_owned(),
+                    self.token.as_ref().unwrap().access_token.to_owned(),
+                )]),
+            )?
+            .into_entity()?;
+        let decrypted_string =
+            RecAuth::aes_decrypt(&client.config().aes_key, &entity.msg_encrypt, false)?;
         info!("{}", decrypted_string);
         let refresh_auth = serde_json::from_str::<RecUserAuthRefreshResponse>(&decrypted_string)?;
         self.token = Some(Token {
@@ -1,21 +1,121 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use log::info;
+use reqwest::blocking::Response;
+use reqwest::header::RANGE;
+use reqwest::StatusCode;
 use serde_json::json;
 
-use crate::{fid::Fid, status_check};
+use crate::fid::Fid;
 
+use super::error::{RecError, RecResult};
 use super::RecClient;
 
+// files asked for in one download request
+const URL_BATCH: usize = 100;
+
+// a download url given by rec, which expires after a while
+pub(super) struct DownloadUrl {
+    url: String,
+    fetched: Instant,
+}
+
 impl RecClient {
-    pub fn get_download_url(&self, fid: Fid) -> anyhow::Result<String> {
-        let resp = self.post::<_, serde_json::Value>(
-            "download",
-            &json!({
-                "files_list": [fid.to_string()]
-            }),
-        )?;
-        status_check!(resp);
-        let url = resp.entity[fid.to_string()]
-            .as_str()
-            .ok_or_else(|| anyhow::anyhow!("Failed to get download url for fid: {}", fid))?;
-        Ok(url.to_owned())
+    pub fn get_download_url(&self, fid: Fid) -> RecResult<String> {
+        self.get_download_urls(&[fid])?.remove(&fid).ok_or_else(|| {
+            RecError::Invalid(format!("Failed to get download url for fid: {}", fid))
+        })
+    }
+
+    // ask rec for the download urls of several files at once, and keep them for download_range()
+    pub fn get_download_urls(&self, fids: &[Fid]) -> RecResult<HashMap<Fid, String>> {
+        let entity = self
+            .post::<_, serde_json::Value>(
+                "download",
+                &json!({
+                    "files_list": fids.iter().map(|fid| fid.to_string()).collect::<Vec<_>>()
+                }),
+            )?
+            .into_entity()?;
+        let urls: HashMap<Fid, String> = fids
+            .iter()
+            .filter_map(|fid| Some((*fid, entity[fid.to_string()].as_str()?.to_owned())))
+            .collect();
+        let fetched = Instant::now();
+        let mut cached = self.download_urls.lock().unwrap();
+        for (fid, url) in urls.iter() {
+            cached.insert(
+                *fid,
+                DownloadUrl {
+                    url: url.clone(),
+                    fetched,
+                },
+            );
+        }
+        Ok(urls)
+    }
+
+    // get the urls of the files about to be downloaded which are not known yet, in batches
+    pub fn resolve_download_urls(&self, fids: &[Fid]) -> RecResult<()> {
+        let missing: Vec<Fid> = fids
+            .iter()
+            .filter(|fid| self.cached_download_url(**fid).is_none())
+            .cloned()
+            .collect();
+        for batch in missing.chunks(URL_BATCH) {
+            info!("Resolve download urls of {} files", batch.len());
+            self.get_download_urls(batch)?;
+        }
+        Ok(())
+    }
+
+    fn cached_download_url(&self, fid: Fid) -> Option<String> {
+        let mut cached = self.download_urls.lock().unwrap();
+        match cached.get(&fid) {
+            Some(url) if url.fetched.elapsed() < self.config.download_url_ttl => {
+                Some(url.url.clone())
+            }
+            Some(_) => {
+                cached.remove(&fid);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn download_url(&self, fid: Fid) -> RecResult<String> {
+        match self.cached_download_url(fid) {
+            Some(url) => Ok(url),
+            None => self.get_download_url(fid),
+        }
+    }
+
+    // fetch bytes start..=end of a file, with the same HTTP client used for API requests
+    // the response is 206 with only that range, or 200 with the whole file if the server
+    // does not support ranges
+    pub fn download_range(&self, fid: Fid, start: u64, end: u64) -> RecResult<Response> {
+        match self.get_range(&self.download_url(fid)?, start, end) {
+            // the url has expired before download_url_ttl: ask rec for a new one
+            Err(RecError::Http(403)) => {
+                info!("Download url of {} has expired", fid);
+                self.download_urls.lock().unwrap().remove(&fid);
+                self.get_range(&self.get_download_url(fid)?, start, end)
+            }
+            res => res,
+        }
+    }
+
+    fn get_range(&self, url: &str, start: u64, end: u64) -> RecResult<Response> {
+        info!("GET (download) {} bytes {}-{}", url, start, end);
+        let res = self
+            .client
+            .get(url)
+            .header(RANGE, format!("bytes={}-{}", start, end))
+            .send()?;
+        if res.status() != StatusCode::PARTIAL_CONTENT && res.status() != StatusCode::OK {
+            return Err(RecError::Http(res.status().as_u16()));
+        }
+        Ok(res)
     }
 }
@@ -0,0 +1,218 @@
+use std::fmt::{Display, Formatter};
+
+use reqwest::StatusCode;
+
+pub type RecResult<T> = Result<T, RecError>;
+
+#[derive(Debug)]
+pub enum RecError {
+    // rec answered, but with a status_code other than 200
+    Api {
+        http_status: u16,
+        status_code: i32,
+        message: String,
+    },
+    // an HTTP error without a rec response body (e.g. from the storage backend)
+    Http(u16),
+    // the request itself failed: DNS, connection reset, timeout...
+    Network(reqwest::Error),
+    Io(std::io::Error),
+    // rec answered something we cannot understand
+    Invalid(String),
+    // login, token refreshing or keyring failure
+    Auth(String),
+    // the md5 of local content differs from the hash given by rec
+    HashMismatch {
+        expected: String,
+        actual: String,
+    },
+}
+
+impl RecError {
+    // whether the request may succeed if sent again
+    // non-idempotent requests are only retried if rec surely did not process them
+    pub fn is_retryable(&self, idempotent: bool) -> bool {
+        match self {
+            // rec's 500 is mostly a permanent error with a message, see errno_from_message()
+            RecError::Api { status_code, .. } => matches!(*status_code, 429 | 503),
+            RecError::Http(status) => match *status {
+                429 | 503 => true,
+                408 | 500 | 502 | 504 => idempotent,
+                _ => false,
+            },
+            RecError::Network(e) => idempotent || e.is_connect(),
+            // a response body cut short
+            RecError::Io(e) => idempotent && e.kind() == std::io::ErrorKind::UnexpectedEof,
+            _ => false,
+        }
+    }
+
+    // whether rec could not be reached at all, rather than failing the request
+    pub fn is_unreachable(&self) -> bool {
+        match self {
+            RecError::Network(e) => e.is_connect() || e.is_timeout(),
+            _ => false,
+        }
+    }
+
+    pub fn errno(&self) -> libc::c_int {
+        match self {
+            RecError::Api {
+                status_code,
+                message,
+                ..
+            } => match *status_code {
+                401 | 403 => libc::EACCES,
+                404 => libc::ENOENT,
+                409 => libc::EEXIST,
+                413 | 507 => libc::ENOSPC,
+                408 | 504 => libc::ETIMEDOUT,
+                429 | 503 => libc::EAGAIN,
+                // rec is not consistent on status codes, so also look into the message
+                _ => errno_from_message(message),
+            },
+            RecError::Http(status) => match StatusCode::from_u16(*status) {
+                Ok(StatusCode::UNAUTHORIZED) | Ok(StatusCode::FORBIDDEN) => libc::EACCES,
+                Ok(StatusCode::NOT_FOUND) | Ok(StatusCode::GONE) => libc::ENOENT,
+                Ok(StatusCode::PAYLOAD_TOO_LARGE) | Ok(StatusCode::INSUFFICIENT_STORAGE) => {
+                    libc::ENOSPC
+                }
+                Ok(StatusCode::REQUEST_TIMEOUT) | Ok(StatusCode::GATEWAY_TIMEOUT) => {
+                    libc::ETIMEDOUT
+                }
+                Ok(StatusCode::TOO_MANY_REQUESTS) | Ok(StatusCode::SERVICE_UNAVAILABLE) => {
+                    libc::EAGAIN
+                }
+                _ => libc::EIO,
+            },
+            RecError::Network(e) => {
+                if e.is_timeout() {
+                    libc::ETIMEDOUT
+                } else if e.is_connect() {
+                    libc::ENETUNREACH
+                } else {
+                    libc::EIO
+                }
+            }
+            RecError::Io(e) => e.raw_os_error().unwrap_or(libc::EIO),
+            RecError::Invalid(_) => libc::EIO,
+            RecError::Auth(_) => libc::EACCES,
+            RecError::HashMismatch { .. } => libc::EIO,
+        }
+    }
+}
+
+fn errno_from_message(message: &str) -> libc::c_int {
+    let message = message.to_lowercase();
+    let matches = |keywords: &[&str]| keywords.iter().any(|k| message.contains(k));
+    if matches(&["空间不足", "容量不足", "insufficient space", "quota"]) {
+        libc::ENOSPC
+    } else if matches(&["已存在", "重名", "already exists"]) {
+        libc::EEXIST
+    } else if matches(&["不存在", "not found"]) {
+        libc::ENOENT
+    } else if matches(&["不为空", "not empty"]) {
+        libc::ENOTEMPTY
+    } else if matches(&["权限", "permission", "forbidden"]) {
+        libc::EACCES
+    } else {
+        libc::EIO
+    }
+}
+
+impl Display for RecError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecError::Api {
+                http_status,
+                status_code,
+                message,
+            } => write!(
+                f,
+                "rec returned status code {} (HTTP {}): {}",
+                status_code, http_status, message
+            ),
+            RecError::Http(status) => write!(f, "HTTP status {}", status),
+            RecError::Network(e) => write!(f, "request failed: {}", e),
+            RecError::Io(e) => write!(f, "I/O error: {}", e),
+            RecError::Invalid(s) => write!(f, "unexpected response: {}", s),
+            RecError::Auth(s) => write!(f, "authentication failed: {}", s),
+            RecError::HashMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "content hash {} differs from {} on rec",
+                    actual, expected
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for RecError {}
+
+impl From<reqwest::Error> for RecError {
+    fn from(e: reqwest::Error) -> Self {
+        match e.status() {
+            Some(status) => RecError::Http(status.as_u16()),
+            None => RecError::Network(e),
+        }
+    }
+}
+
+impl From<std::io::Error> for RecError {
+    fn from(e: std::io::Error) -> Self {
+        RecError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for RecError {
+    fn from(e: serde_json::Error) -> Self {
+        RecError::Invalid(e.to_string())
+    }
+}
+
+impl From<std::num::ParseIntError> for RecError {
+    fn from(e: std::num::ParseIntError) -> Self {
+        RecError::Invalid(e.to_string())
+    }
+}
+
+impl From<keyring::Error> for RecError {
+    fn from(e: keyring::Error) -> Self {
+        RecError::Auth(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errno() {
+        let api = |status_code, message: &str| RecError::Api {
+            http_status: 200,
+            status_code,
+            message: message.to_owned(),
+        };
+        assert_eq!(api(401, "token expired").errno(), libc::EACCES);
+        assert_eq!(api(404, "").errno(), libc::ENOENT);
+        assert_eq!(api(500, "文件夹已存在").errno(), libc::EEXIST);
+        assert_eq!(api(500, "用户空间不足").errno(), libc::ENOSPC);
+        assert_eq!(api(500, "unknown").errno(), libc::EIO);
+        assert_eq!(RecError::Http(504).errno(), libc::ETIMEDOUT);
+        assert_eq!(RecError::Http(502).errno(), libc::EIO);
+    }
+
+    #[test]
+    fn test_retryable() {
+        let api = |status_code| RecError::Api {
+            http_status: 200,
+            status_code,
+            message: String::new(),
+        };
+        assert!(api(503).is_retryable(false));
+        assert!(!api(500).is_retryable(true));
+        assert!(RecError::Http(500).is_retryable(true));
+        assert!(!RecError::Http(500).is_retryable(false));
+    }
+}
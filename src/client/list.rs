@@ -1,7 +1,7 @@
+use super::error::{RecError, RecResult};
 use super::{filename, RecClient};
 use crate::client::filetype;
 use crate::fid::Fid;
-use crate::status_check;
 use fuse_mt::FileType;
 use log::debug;
 use serde::Deserialize;
@@ -53,10 +53,23 @@ impl RecListItem {
             time_updated: SystemTime::UNIX_EPOCH,
         }
     }
+
+    // identifies the content of a file: its hash, or the update time if rec gives no hash
+    pub fn version(&self) -> String {
+        match &self.hash {
+            Some(hash) => hash.clone(),
+            None => format!(
+                "{}",
+                self.time_updated
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs())
+            ),
+        }
+    }
 }
 
 impl TryFrom<RecListData> for RecListItem {
-    type Error = anyhow::Error;
+    type Error = RecError;
 
     fn try_from(data: RecListData) -> Result<Self, Self::Error> {
         let time = PrimitiveDateTime::parse(
@@ -69,7 +82,7 @@ impl TryFrom<RecListData> for RecListItem {
             bytes: match data.bytes {
                 Value::String(_) => 0,
                 Value::Number(i) => i.as_u64().unwrap() as usize,
-                v => return Err(anyhow::Error::msg(format!("Invalid bytes field: {}", v))),
+                v => return Err(RecError::Invalid(format!("Invalid bytes field: {}", v))),
             },
             name: filename(data.name, data.file_ext),
             hash: if data.hash.is_empty() {
@@ -77,7 +90,8 @@ impl TryFrom<RecListData> for RecListItem {
             } else {
                 Some(data.hash)
             },
-            fid: Fid::from_str(data.number.as_str())?,
+            fid: Fid::from_str(data.number.as_str())
+                .map_err(|e| RecError::Invalid(e.to_string()))?,
             ftype: filetype(data.ftype.as_str())?,
             time_updated: time.into(),
         })
@@ -85,41 +99,41 @@ impl TryFrom<RecListData> for RecListItem {
 }
 
 impl RecClient {
-    pub fn list(&self, fid: Fid) -> anyhow::Result<Vec<RecListItem>> {
+    pub fn list(&self, fid: Fid) -> RecResult<Vec<RecListItem>> {
         let path = if fid.to_string() == "B_0" {
             "folder/content/0".to_owned()
         } else {
             format!("folder/content/{}", fid)
         };
-        let body = self.get::<_, RecListEntity>(
-            &path,
-            &[
-                (
-                    "disk_type",
-                    match fid.to_string().as_str() {
-                        "B_0" => "backup",
-                        "R_0" => "recycle",
-                        _ => "cloud",
-                    },
-                ),
-                ("is_rec", "false"),
-                ("category", "all"),
-            ],
-        )?;
-        debug!("list() body: {:?}", body);
-        status_check!(body);
-        let mut items = body
-            .entity
+        let entity = self
+            .get::<_, RecListEntity>(
+                &path,
+                &[
+                    (
+                        "disk_type",
+                        match fid.to_string().as_str() {
+                            "B_0" => "backup",
+                            "R_0" => "recycle",
+                            _ => "cloud",
+                        },
+                    ),
+                    ("is_rec", "false"),
+                    ("category", "all"),
+                ],
+            )?
+            .into_entity()?;
+        debug!("list() entity: {:?}", entity);
+        let mut items = entity
             .datas
             .into_iter()
             .map(RecListItem::try_from)
-            .collect::<anyhow::Result<Vec<RecListItem>>>()?;
+            .collect::<RecResult<Vec<RecListItem>>>()?;
         if fid == Fid::root() {
             items.push(RecListItem {
                 bytes: 0,
                 name: "?Backup".to_string(),
                 hash: None,
-                fid: "B_0".parse()?,
+                fid: "B_0".parse().unwrap(),
                 ftype: FileType::Directory,
                 time_updated: SystemTime::UNIX_EPOCH,
             });
@@ -127,7 +141,7 @@ impl RecClient {
                 bytes: 0,
                 name: "?Recycle".to_string(),
                 hash: None,
-                fid: "R_0".parse()?,
+                fid: "R_0".parse().unwrap(),
                 ftype: FileType::Directory,
                 time_updated: SystemTime::UNIX_EPOCH,
             });
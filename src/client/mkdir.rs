@@ -1,20 +1,21 @@
 use serde_json::json;
 
-use crate::{fid::Fid, status_check};
+use crate::fid::Fid;
 
+use super::error::RecResult;
 use super::RecClient;
 
 impl RecClient {
-    pub fn mkdir(&self, parent: Fid, name: String) -> anyhow::Result<()> {
-        let resp = self.post::<_, serde_json::Value>(
+    pub fn mkdir(&self, parent: Fid, name: String) -> RecResult<()> {
+        self.post::<_, serde_json::Value>(
             "folder/tree",
             &json!({
                 "disk_type": "cloud",
                 "number": parent.to_string(),
                 "paramslist": [name]
             }),
-        )?;
-        status_check!(resp);
+        )?
+        .into_entity()?;
         Ok(())
     }
 }
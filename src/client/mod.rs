@@ -1,49 +1,112 @@
 pub mod auth;
 pub mod download;
+pub mod error;
 pub mod list;
 pub mod mkdir;
 pub mod operation;
 pub mod stat;
 pub mod upload;
 
+use std::collections::HashMap;
+use std::convert::TryInto;
 use std::fmt::Debug;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use binary_macros::base64;
 use fuse_mt::FileType;
 use log::{debug, info, warn};
-use reqwest::blocking::Client;
+use rand::Rng;
+use reqwest::blocking::{Client, Response};
 // use reqwest::header::{CONTENT_LENGTH, USER_AGENT};
 use serde::Deserializer;
 use serde::{Deserialize, Serialize};
 
 use self::auth::RecAuth;
+use self::download::DownloadUrl;
+use self::error::{RecError, RecResult};
+use crate::fid::Fid;
 
-const APIURL: &str = "https://recapi.ustc.edu.cn/api/v2/";
-const CLIENTID: &str = "d5485a8c-fecb-11e9-b690-005056b70c02";
-const SIGNATURE: &str = "VZPDF6HxKyh0hhqFqY2Tk6udzlambRgK";
-static AESKEY: &[u8; 16] = base64!("Z1pNbFZmMmVqd2wwVmlHNA==");
+pub const APIURL: &str = "https://recapi.ustc.edu.cn/api/v2/";
+pub const CLIENTID: &str = "d5485a8c-fecb-11e9-b690-005056b70c02";
+pub const SIGNATURE: &str = "VZPDF6HxKyh0hhqFqY2Tk6udzlambRgK";
+pub const AESKEY: &str = "Z1pNbFZmMmVqd2wwVmlHNA==";
 
 type EmptyQuery = [(String, String); 0];
 
-#[macro_export]
-macro_rules! status_check {
-    ($x: expr) => {
-        if $x.status_code != 200 {
-            $crate::client::warn!("Get error message from rec: {}", $x.message);
-            return Err(anyhow::anyhow!(
-                "Status code {}, error message: {}",
-                $x.status_code,
-                $x.message
-            ));
+// POST endpoints which have no side effect and can be sent again safely
+const IDEMPOTENT_POSTS: &[&str] = &["download"];
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    // total number of tries, including the first one
+    pub attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 4,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
         }
-    };
+    }
+}
+
+impl RetryPolicy {
+    // exponential backoff with "equal jitter": half of the delay is randomized
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_delay);
+        delay / 2 + delay.mul_f64(rand::thread_rng().gen_range(0.0..0.5))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RecConfig {
+    pub api_url: String,
+    pub client_id: String,
+    pub signature: String,
+    pub aes_key: [u8; 16],
+    pub retry: RetryPolicy,
+    // chunks of a file uploaded at the same time
+    pub upload_parallelism: usize,
+    // download urls are asked for again after this long
+    pub download_url_ttl: Duration,
+}
+
+impl Default for RecConfig {
+    fn default() -> Self {
+        Self {
+            api_url: APIURL.to_owned(),
+            client_id: CLIENTID.to_owned(),
+            signature: SIGNATURE.to_owned(),
+            aes_key: RecConfig::parse_aes_key(AESKEY).unwrap(),
+            retry: RetryPolicy::default(),
+            upload_parallelism: 4,
+            download_url_ttl: Duration::from_secs(600),
+        }
+    }
+}
+
+impl RecConfig {
+    // the key is given in base64, as the one in the Windows client
+    pub fn parse_aes_key(key: &str) -> anyhow::Result<[u8; 16]> {
+        let key = base64::decode(key)?;
+        key.as_slice()
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("AES key must be 16 bytes, got {}", key.len()))
+    }
 }
 
 pub struct RecClient {
     pub auth: Arc<Mutex<RecAuth>>,
     client: Client,
+    config: RecConfig,
+    download_urls: Mutex<HashMap<Fid, DownloadUrl>>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -57,6 +120,53 @@ where
     // message exists even when not failed
     message: String,
     status_code: i32,
+    #[serde(skip)]
+    http_status: u16,
+}
+
+impl<T> RecRes<T>
+where
+    T: Default + for<'a> Deserialize<'a>,
+{
+    // the entity if rec reports success, otherwise the error from rec
+    pub fn into_entity(self) -> RecResult<T> {
+        if self.status_code != 200 {
+            warn!("Get error message from rec: {}", self.message);
+            return Err(RecError::Api {
+                http_status: self.http_status,
+                status_code: self.status_code,
+                message: self.message,
+            });
+        }
+        Ok(self.entity)
+    }
+
+    // temporary failures reported by rec become errors, so that they can be retried
+    // rec reports semantic errors (e.g. a folder already exists) as 500 too, which are not
+    fn transient_to_err(self) -> RecResult<Self> {
+        match self.status_code {
+            429 | 503 => Err(RecError::Api {
+                http_status: self.http_status,
+                status_code: self.status_code,
+                message: self.message,
+            }),
+            _ => Ok(self),
+        }
+    }
+
+    fn from_response(res: Response) -> RecResult<Self> {
+        let http_status = res.status();
+        let text = res.text()?;
+        debug!("Response ({}): {}", http_status, text);
+        match serde_json::from_str::<RecRes<T>>(text.trim_start_matches('\u{feff}')) {
+            Ok(mut body) => {
+                body.http_status = http_status.as_u16();
+                body.transient_to_err()
+            }
+            Err(_) if !http_status.is_success() => Err(RecError::Http(http_status.as_u16())),
+            Err(e) => Err(e.into()),
+        }
+    }
 }
 
 fn failure_to_default<'de, D, T>(de: D) -> Result<T, D::Error>
@@ -76,133 +186,183 @@ where
 
 impl Default for RecClient {
     fn default() -> Self {
+        Self::new(RecConfig::default())
+    }
+}
+
+impl RecClient {
+    pub fn new(mut config: RecConfig) -> Self {
+        if !config.api_url.ends_with('/') {
+            config.api_url.push('/');
+        }
         Self {
             auth: Arc::new(Mutex::new(RecAuth::default())),
             client: Client::builder()
                 .timeout(Duration::from_secs(120))
                 .build()
                 .unwrap(),
+            config,
+            download_urls: Mutex::new(HashMap::new()),
         }
     }
-}
 
-impl RecClient {
+    pub fn config(&self) -> &RecConfig {
+        &self.config
+    }
+
     pub fn set_auth(&mut self, auth: RecAuth) {
         *self.auth.lock().unwrap() = auth;
     }
 
-    pub fn get_noretry<T: Serialize + ?Sized + Debug, S: for<'a> Deserialize<'a> + Default>(
+    // run f until it succeeds, fails permanently or runs out of attempts
+    // non-idempotent requests are only retried when they cannot have reached rec
+    pub fn with_retry<T>(
+        &self,
+        what: &str,
+        idempotent: bool,
+        mut f: impl FnMut() -> RecResult<T>,
+    ) -> RecResult<T> {
+        let policy = &self.config.retry;
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Err(e) if attempt + 1 < policy.attempts && e.is_retryable(idempotent) => {
+                    let delay = policy.delay(attempt);
+                    attempt += 1;
+                    warn!(
+                        "{} failed (attempt {}/{}): {}, retrying in {:?}",
+                        what, attempt, policy.attempts, e, delay
+                    );
+                    std::thread::sleep(delay);
+                }
+                res => return res,
+            }
+        }
+    }
+
+    // a request retried as with_retry() does, but not again after refreshing the token on a 401
+    // (e.g. the requests of the login and refresh themselves)
+    pub fn get_without_refresh<
+        T: Serialize + ?Sized + Debug,
+        S: for<'a> Deserialize<'a> + Default,
+    >(
         &self,
         path: &str,
         token: bool,
         query: &T,
-    ) -> anyhow::Result<RecRes<S>> {
+    ) -> RecResult<RecRes<S>> {
         info!("GET {} with query {:?}", path, query);
-        let url = format!("{}{}", APIURL, path);
-        let mut builder = self.client.get(url);
-        if token {
-            let auth = self.auth.clone();
-            let auth = auth.lock().unwrap();
-            builder = builder.header(
-                "x-auth-token",
-                auth.token.as_ref().unwrap().access_token.as_str(),
-            );
-        }
-        let res = builder.query(query).send()?;
-        let text = res.text()?;
-        debug!("GET Response: {}", text);
-        let body = serde_json::from_str::<RecRes<S>>(text.trim_start_matches('\u{feff}'))?;
-
-        Ok(body)
+        let url = format!("{}{}", self.config.api_url, path);
+        self.with_retry(&format!("GET {}", path), true, || {
+            let mut builder = self.client.get(&url);
+            if token {
+                let auth = self.auth.clone();
+                let auth = auth.lock().unwrap();
+                builder = builder.header(
+                    "x-auth-token",
+                    auth.token.as_ref().unwrap().access_token.as_str(),
+                );
+            }
+            let res = builder.query(query).send()?;
+            RecRes::from_response(res)
+        })
     }
 
     pub fn get<T: Serialize + ?Sized + Debug, S: for<'a> Deserialize<'a> + Default>(
         &self,
         path: &str,
         query: &T,
-    ) -> anyhow::Result<RecRes<S>> {
-        let res = self.get_noretry(path, true, query)?;
+    ) -> RecResult<RecRes<S>> {
+        let res = self.get_without_refresh(path, true, query)?;
         if res.status_code == 401 {
             {
                 let auth = self.auth.clone();
                 let mut auth = auth.lock().unwrap();
                 auth.refresh(self)?;
             }
-            Ok(self.get_noretry(path, true, query)?)
+            Ok(self.get_without_refresh(path, true, query)?)
         } else {
             Ok(res)
         }
     }
 
-    pub fn post_noretry<T: Serialize + ?Sized + Debug, S: for<'a> Deserialize<'a> + Default>(
+    // as get_without_refresh()
+    pub fn post_without_refresh<
+        T: Serialize + ?Sized + Debug,
+        S: for<'a> Deserialize<'a> + Default,
+    >(
         &self,
         path: &str,
         token: bool,
         json: &T,
         headers: Option<&[(String, String)]>,
-    ) -> anyhow::Result<RecRes<S>> {
+    ) -> RecResult<RecRes<S>> {
         assert!(!(token && headers.is_some()));
         info!("POST {} with json {:?}", path, json);
-        let url = format!("{}{}", APIURL, path);
-        let mut builder = self.client.post(url);
-        if token {
-            let auth = self.auth.clone();
-            let auth = auth.lock().unwrap();
-            builder = builder.header(
-                "x-auth-token",
-                auth.token.as_ref().unwrap().access_token.as_str(),
-            );
-        }
-        if let Some(headers) = headers {
-            for (key, value) in headers {
-                builder = builder.header(key, value);
+        let url = format!("{}{}", self.config.api_url, path);
+        let idempotent = IDEMPOTENT_POSTS.contains(&path);
+        self.with_retry(&format!("POST {}", path), idempotent, || {
+            let mut builder = self.client.post(&url);
+            if token {
+                let auth = self.auth.clone();
+                let auth = auth.lock().unwrap();
+                builder = builder.header(
+                    "x-auth-token",
+                    auth.token.as_ref().unwrap().access_token.as_str(),
+                );
             }
-        }
-        let res = builder.json(json).send()?;
-        let text = res.text()?;
-        debug!("POST Response: {}", text);
-        let body = serde_json::from_str::<RecRes<S>>(text.trim_start_matches('\u{feff}'))?;
-
-        Ok(body)
+            if let Some(headers) = headers {
+                for (key, value) in headers {
+                    builder = builder.header(key, value);
+                }
+            }
+            let res = builder.json(json).send()?;
+            RecRes::from_response(res)
+        })
     }
 
     pub fn post<T: Serialize + ?Sized + Debug, S: for<'a> Deserialize<'a> + Default>(
         &self,
         path: &str,
         json: &T,
-    ) -> anyhow::Result<RecRes<S>> {
-        let res = self.post_noretry(path, true, json, None)?;
+    ) -> RecResult<RecRes<S>> {
+        let res = self.post_without_refresh(path, true, json, None)?;
         if res.status_code == 401 {
             {
                 let auth = self.auth.clone();
                 let mut auth = auth.lock().unwrap();
                 auth.refresh(self)?;
             }
-            Ok(self.post_noretry(path, true, json, None)?)
+            Ok(self.post_without_refresh(path, true, json, None)?)
         } else {
             Ok(res)
         }
     }
 
-    pub fn put_upload(&self, url: &str, data: Vec<u8>) -> anyhow::Result<()> {
+    pub fn put_upload(&self, url: &str, data: &[u8]) -> RecResult<()> {
         info!("PUT (upload) {}", url);
-        let mut builder = self.client.put(url);
-        {
-            let auth = self.auth.clone();
-            let auth = auth.lock().unwrap();
-            builder = builder.header(
-                "x-auth-token",
-                auth.token.as_ref().unwrap().access_token.as_str(),
-            );
-        }
-        // let len = data.len();
-        let res = builder
-            .body(data)
-            // .header(USER_AGENT, "recfs (FUSE implementation)")
-            // .header(CONTENT_LENGTH, len.to_string())
-            .send()?;
-        res.error_for_status()?;
-        Ok(())
+        // PUT of the same chunk is idempotent
+        self.with_retry("PUT (upload)", true, || {
+            let mut builder = self.client.put(url);
+            {
+                let auth = self.auth.clone();
+                let auth = auth.lock().unwrap();
+                builder = builder.header(
+                    "x-auth-token",
+                    auth.token.as_ref().unwrap().access_token.as_str(),
+                );
+            }
+            // let len = data.len();
+            let res = builder
+                .body(data.to_vec())
+                // .header(USER_AGENT, "recfs (FUSE implementation)")
+                // .header(CONTENT_LENGTH, len.to_string())
+                .send()?;
+            if !res.status().is_success() {
+                return Err(RecError::Http(res.status().as_u16()));
+            }
+            Ok(())
+        })
     }
 }
 
@@ -214,10 +374,24 @@ pub fn filename(name: String, ext: String) -> String {
     }
 }
 
-pub fn filetype(ftype: &str) -> anyhow::Result<FileType> {
+pub fn filetype(ftype: &str) -> RecResult<FileType> {
     match ftype {
         "folder" => Ok(FileType::Directory),
         "file" => Ok(FileType::RegularFile),
-        _ => Err(anyhow::Error::msg("Unknown file type ".to_owned() + ftype)),
+        _ => Err(RecError::Invalid("Unknown file type ".to_owned() + ftype)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_aes_key() {
+        assert_eq!(
+            &RecConfig::parse_aes_key(AESKEY).unwrap(),
+            b"gZMlVf2ejwl0ViG4"
+        );
+        assert!(RecConfig::parse_aes_key("c2hvcnQ=").is_err());
     }
 }
@@ -1,8 +1,9 @@
 use fuse_mt::FileType;
 use serde_json::json;
 
-use crate::{fid::Fid, status_check};
+use crate::fid::Fid;
 
+use super::error::RecResult;
 use super::RecClient;
 
 #[derive(Debug)]
@@ -33,10 +34,10 @@ impl RecClient {
         from_id: Fid,
         from_type: FileType,
         dst_id: Option<String>,
-    ) -> anyhow::Result<()> {
+    ) -> RecResult<()> {
         let action: String = action.into();
         let dst_id = dst_id.unwrap_or_default();
-        let resp = self.post::<_, serde_json::Value>(
+        self.post::<_, serde_json::Value>(
             "operationFileOrFolder",
             &json!({
                 "action": action,
@@ -48,13 +49,13 @@ impl RecClient {
                 }}],
                 "number": if dst_id == *"B_0" { "0".to_string() } else { dst_id }
             }),
-        )?;
-        status_check!(resp);
+        )?
+        .into_entity()?;
         Ok(())
     }
 
-    pub fn rename(&self, id: Fid, new_name: String, filetype: FileType) -> anyhow::Result<()> {
-        let resp = self.post::<_, serde_json::Value>(
+    pub fn rename(&self, id: Fid, new_name: String, filetype: FileType) -> RecResult<()> {
+        self.post::<_, serde_json::Value>(
             "rename",
             &json!({
                 "name": new_name,
@@ -65,21 +66,21 @@ impl RecClient {
                     _ => unreachable!(),
                 }
             }),
-        )?;
-        status_check!(resp);
+        )?
+        .into_entity()?;
         Ok(())
     }
 
     // https://github.com/taoky/reccli/issues/1
-    pub fn rename_ext(&self, id: Fid, new_name: String) -> anyhow::Result<()> {
-        let resp = self.post::<_, serde_json::Value>(
+    pub fn rename_ext(&self, id: Fid, new_name: String) -> RecResult<()> {
+        self.post::<_, serde_json::Value>(
             "rename_ext",
             &json!({
                 "name": new_name,
                 "number": id.to_string(),
-            })
-        )?;
-        status_check!(resp);
+            }),
+        )?
+        .into_entity()?;
         Ok(())
     }
 }
@@ -1,8 +1,9 @@
 use serde::Deserialize;
 use serde_with::{serde_as, DisplayFromStr};
 
-use crate::{client::EmptyQuery, status_check};
+use crate::client::EmptyQuery;
 
+use super::error::RecResult;
 use super::RecClient;
 
 #[serde_as]
@@ -31,9 +32,8 @@ pub struct RecUserInfo {
 }
 
 impl RecClient {
-    pub fn stat(&self) -> anyhow::Result<RecUserInfo> {
-        let body = self.get::<EmptyQuery, RecUserInfo>("userinfo", &[])?;
-        status_check!(body);
-        Ok(body.entity)
+    pub fn stat(&self) -> RecResult<RecUserInfo> {
+        self.get::<EmptyQuery, RecUserInfo>("userinfo", &[])?
+            .into_entity()
     }
 }
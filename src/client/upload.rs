@@ -1,11 +1,14 @@
-use std::{io::Read, path::Path};
+use std::{
+    fs::File, os::unix::fs::FileExt, path::Path, str::FromStr, sync::Mutex, time::SystemTime,
+};
 
 use log::{info, warn};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use crate::{fid::Fid, status_check};
+use crate::fid::Fid;
 
+use super::error::{RecError, RecResult};
 use super::RecClient;
 
 #[derive(Deserialize)]
@@ -18,62 +21,227 @@ struct RecUploadParam {
 
 type RecUploadParams = Vec<Vec<RecUploadParam>>;
 
+// an upload begun on rec, which can be continued until file/complete is sent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadSession {
+    token: String,
+    chunk_size: u64,
+    // PUT url of each chunk
+    urls: Vec<String>,
+    // chunks uploaded already
+    done: Vec<bool>,
+    // size and modification time (unix milliseconds) of the local file when it began
+    bytes: u64,
+    modified: u64,
+    // number of the file made by file/complete, until a listing confirms it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    completed: Option<String>,
+}
+
+impl UploadSession {
+    // whether the local file is still what is being uploaded
+    pub fn matches(&self, file_path: &Path) -> bool {
+        file_path
+            .metadata()
+            .is_ok_and(|m| m.len() == self.bytes && modified_millis(&m) == self.modified)
+    }
+
+    pub fn completed(&self) -> Option<Fid> {
+        self.completed
+            .as_deref()
+            .and_then(|n| Fid::from_str(n).ok())
+    }
+
+    pub fn done_chunks(&self) -> usize {
+        self.done.iter().filter(|d| **d).count()
+    }
+}
+
+// shared by the threads uploading the chunks of a file
+struct Progress<'a, F> {
+    session: &'a mut UploadSession,
+    save: &'a mut F,
+    error: Option<RecError>,
+}
+
+fn modified_millis(metadata: &std::fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
 impl RecClient {
-    pub fn upload(
+    pub fn upload(&self, parent_fid: Fid, file_path: &Path, file_name: String) -> RecResult<Fid> {
+        self.upload_resumable(parent_fid, file_path, file_name, None, |_| {})
+    }
+
+    // upload a file, continuing the session of an earlier try if there is one
+    // save() is called whenever the session changes, so that it can be resumed after a failure
+    // the new file is returned, which rec names "name(1)" if the name is taken already
+    pub fn upload_resumable(
+        &self,
+        parent_fid: Fid,
+        file_path: &Path,
+        file_name: String,
+        session: Option<UploadSession>,
+        mut save: impl FnMut(&UploadSession) + Send,
+    ) -> RecResult<Fid> {
+        if let Some(mut session) = session.filter(|s| s.matches(file_path) && s.completed.is_none())
+        {
+            info!(
+                "Resume upload of {} from {}/{} chunks",
+                file_name,
+                session.done_chunks(),
+                session.urls.len()
+            );
+            match self.upload_chunks(&mut session, file_path, &mut save) {
+                // rec may still be there for the session next time
+                Err(e) if e.is_unreachable() || e.is_retryable(true) => return Err(e),
+                // the session has expired or is unknown to rec
+                Err(e) => warn!("Failed to resume upload of {}: {}", file_name, e),
+                Ok(fid) => return Ok(fid),
+            }
+        }
+        let mut session = self.begin_upload(parent_fid, file_path, file_name)?;
+        save(&session);
+        self.upload_chunks(&mut session, file_path, &mut save)
+    }
+
+    fn begin_upload(
         &self,
         parent_fid: Fid,
         file_path: &Path,
         file_name: String,
-    ) -> anyhow::Result<()> {
-        let filesize = file_path.metadata()?.len();
-        let resp = self.get::<_, serde_json::Value>(
-            &format!("file/{}", parent_fid),
-            &[
-                ("file_name", file_name),
-                ("byte", filesize.to_string()),
-                ("storage", "moss".to_owned()),
-                ("disk_type", "cloud".to_owned()),
-            ],
-        )?;
-        status_check!(resp);
-        let resp = resp.entity;
+    ) -> RecResult<UploadSession> {
+        let metadata = file_path.metadata()?;
+        let filesize = metadata.len();
+        let resp = self
+            .get::<_, serde_json::Value>(
+                &format!("file/{}", parent_fid),
+                &[
+                    ("file_name", file_name),
+                    ("byte", filesize.to_string()),
+                    ("storage", "moss".to_owned()),
+                    ("disk_type", "cloud".to_owned()),
+                ],
+            )?
+            .into_entity()?;
         let upload_token: String = serde_json::from_value(resp["upload_token"].clone())?;
 
         let upload_chunk_size: String = serde_json::from_value(resp["upload_chunk_size"].clone())?;
-        let upload_chunk_size: usize = upload_chunk_size.parse()?;
+        let upload_chunk_size: u64 = upload_chunk_size.parse()?;
 
         let upload_params: RecUploadParams = serde_json::from_value(resp["upload_params"].clone())?;
-
-        let file = std::fs::File::open(file_path)?;
-
-        for (idx, i) in upload_params.into_iter().enumerate() {
-            let mut buffer: Vec<u8> = Vec::new();
-            let mut file_partial = file.try_clone()?.take(upload_chunk_size as u64);
-            let size = file_partial.read_to_end(&mut buffer)?;
-            buffer.resize(size, 0);
-
-            let upload_url = &i[1].value;
+        let mut urls = Vec::new();
+        for i in upload_params {
             let upload_method = &i[2].value;
             if upload_method != "PUT" {
-                return Err(anyhow::anyhow!(
+                return Err(RecError::Invalid(format!(
                     "Unsupported upload method: {}",
                     upload_method
-                ));
+                )));
             }
-            if let Err(e) = self.put_upload(upload_url, buffer) {
-                warn!("Upload part {} err with {}", idx, e);
-                return Err(e);
-            } else {
-                info!("Upload part {} ok", idx);
+            urls.push(i[1].value.clone());
+        }
+
+        Ok(UploadSession {
+            token: upload_token,
+            chunk_size: upload_chunk_size,
+            done: vec![false; urls.len()],
+            urls,
+            bytes: filesize,
+            modified: modified_millis(&metadata),
+            completed: None,
+        })
+    }
+
+    // PUT the chunks not uploaded yet, several at a time, then send file/complete
+    // each worker reads its chunk when it is about to send it, so that at most
+    // upload_parallelism chunks are in memory
+    fn upload_chunks(
+        &self,
+        session: &mut UploadSession,
+        file_path: &Path,
+        save: &mut (impl FnMut(&UploadSession) + Send),
+    ) -> RecResult<Fid> {
+        let file = File::open(file_path)?;
+        let todo: Vec<usize> = (0..session.urls.len())
+            .filter(|idx| !session.done[*idx])
+            .collect();
+        let workers = self.config.upload_parallelism.max(1).min(todo.len());
+        let next = Mutex::new(todo.into_iter());
+        let progress = Mutex::new(Progress {
+            session,
+            save,
+            error: None,
+        });
+        std::thread::scope(|s| {
+            for _ in 0..workers {
+                s.spawn(|| loop {
+                    let idx = match next.lock().unwrap().next() {
+                        Some(idx) => idx,
+                        None => return,
+                    };
+                    let (url, offset, size) = {
+                        let progress = progress.lock().unwrap();
+                        if progress.error.is_some() {
+                            // another chunk has failed
+                            return;
+                        }
+                        let session = &progress.session;
+                        let offset = idx as u64 * session.chunk_size;
+                        let size = session.chunk_size.min(session.bytes.saturating_sub(offset));
+                        (session.urls[idx].clone(), offset, size)
+                    };
+                    let mut buffer = vec![0; size as usize];
+                    let res = file
+                        .read_exact_at(&mut buffer, offset)
+                        .map_err(RecError::from)
+                        .and_then(|_| self.put_upload(&url, &buffer));
+                    let mut progress = progress.lock().unwrap();
+                    let Progress {
+                        session,
+                        save,
+                        error,
+                    } = &mut *progress;
+                    match res {
+                        Ok(()) => {
+                            info!("Upload part {} ok", idx);
+                            session.done[idx] = true;
+                            save(session);
+                        }
+                        Err(e) => {
+                            warn!("Upload part {} err with {}", idx, e);
+                            error.get_or_insert(e);
+                            return;
+                        }
+                    }
+                });
             }
+        });
+        let Progress {
+            session,
+            save,
+            error,
+        } = progress.into_inner().unwrap();
+        if let Some(e) = error {
+            return Err(e);
         }
 
-        let resp = self.post::<_, serde_json::Value>(
-            "file/complete",
-            &json!({ "upload_token": upload_token }),
-        )?;
-        status_check!(resp);
+        let resp = self
+            .post::<_, serde_json::Value>(
+                "file/complete",
+                &json!({ "upload_token": session.token }),
+            )?
+            .into_entity()?;
+        let number: String = serde_json::from_value(resp["number"].clone())?;
+        let fid = Fid::from_str(&number).map_err(|e| RecError::Invalid(e.to_string()))?;
+        // so that a failure after this point never uploads it twice
+        session.completed = Some(number);
+        save(session);
 
-        Ok(())
+        Ok(fid)
     }
 }
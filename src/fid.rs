@@ -26,6 +26,16 @@ impl Fid {
     pub fn is_created(&self) -> bool {
         matches!(self.id, FidValue::Write(_))
     }
+
+    // the disk_type of rec whose root is this Fid
+    pub fn root_disk(&self) -> Option<&'static str> {
+        match self.id {
+            FidValue::Root => Some("cloud"),
+            FidValue::BackupRoot => Some("backup"),
+            FidValue::RecycleRoot => Some("recycle"),
+            _ => None,
+        }
+    }
 }
 
 impl Display for Fid {
@@ -1,31 +1,151 @@
 use crate::{client::list::RecListItem, fid::Fid};
-use bimap::BiBTreeMap;
-use std::collections::HashMap;
+use fuse_mt::FileType;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 #[derive(Debug, Clone, Default)]
 pub struct FidCachedList {
     pub children: Option<Vec<RecListItem>>, // None => type is not dir
+    pub listed: Option<Instant>,            // when children were fetched from rec
+    pub used: Option<Instant>,              // when it or one of its children was last opened
+    pub restored: Option<SystemTime>, // when children were fetched, if loaded from the metadata file
+}
+
+// on-disk form of the listings, see FidMap::save()
+#[derive(Serialize, Deserialize)]
+struct SavedListing {
+    fid: String,
+    parent: Option<String>,
+    listed: u64, // unix time
+    children: Vec<SavedItem>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedItem {
+    fid: String,
+    name: String,
+    bytes: usize,
+    hash: Option<String>,
+    dir: bool,
+    updated: u64, // unix time
+}
+
+fn unix_time(t: SystemTime) -> u64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+impl From<&RecListItem> for SavedItem {
+    fn from(item: &RecListItem) -> Self {
+        Self {
+            fid: item.fid.to_string(),
+            name: item.name.clone(),
+            bytes: item.bytes,
+            hash: item.hash.clone(),
+            dir: item.ftype == FileType::Directory,
+            updated: unix_time(item.time_updated),
+        }
+    }
+}
+
+impl TryFrom<SavedItem> for RecListItem {
+    type Error = anyhow::Error;
+
+    fn try_from(item: SavedItem) -> Result<Self, Self::Error> {
+        Ok(Self {
+            bytes: item.bytes,
+            name: item.name,
+            hash: item.hash,
+            fid: item.fid.parse()?,
+            ftype: if item.dir {
+                FileType::Directory
+            } else {
+                FileType::RegularFile
+            },
+            time_updated: SystemTime::UNIX_EPOCH + Duration::from_secs(item.updated),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Ttl {
+    // a listing older than this is fetched again before being used
+    pub listing: Duration,
+    // a name missing from a listing is not looked up on rec again during this time
+    pub negative: Duration,
+}
+
+impl Default for Ttl {
+    fn default() -> Self {
+        Self {
+            listing: Duration::from_secs(60),
+            negative: Duration::from_secs(5),
+        }
+    }
+}
+
+// state of a file or directory opened by open(), create() or opendir()
+#[derive(Debug)]
+pub struct FileHandle {
+    pub fid: Fid,
+    pub accmode: u32,            // flags & O_ACCMODE given to open()
+    pub file: Option<Arc<File>>, // the local copy, opened on first read or write
+    pub dirty: bool,             // written through this handle
+    pub pos: u64,                // offset right after the last read or write
+}
+
+impl FileHandle {
+    pub fn readable(&self) -> bool {
+        self.accmode != libc::O_WRONLY as u32
+    }
+
+    pub fn writable(&self) -> bool {
+        self.accmode != libc::O_RDONLY as u32
+    }
 }
 
 pub struct FidMap {
-    fhmap: BiBTreeMap<u64, Fid>, // a bidirectional map of "file handle" and Fid
+    handles: BTreeMap<u64, FileHandle>, // a map from "file handle" to its state
+    last_fh: u64,
     listing_map: HashMap<Fid, FidCachedList>, // a map from Fid to the HTTP cache of listing
-    parent_map: HashMap<Fid, Option<Fid>>, // a map from Fid to its parent
+    parent_map: HashMap<Fid, Option<Fid>>,    // a map from Fid to its parent
+    pending: HashMap<Fid, RecListItem>,       // created files which are not uploaded yet
+    unlinked: HashMap<Fid, RecListItem>,      // pending files unlinked while still open
+    missing: HashMap<(Fid, String), Instant>, // names looked up on rec but not found
+    ttl: Ttl,
 }
 
 impl FidMap {
-    pub fn new() -> Self {
+    pub fn new(ttl: Ttl) -> Self {
         let mut fm = Self {
-            fhmap: BiBTreeMap::new(),
+            handles: BTreeMap::new(),
+            last_fh: 3,
             listing_map: HashMap::new(),
             parent_map: HashMap::new(),
+            pending: HashMap::new(),
+            unlinked: HashMap::new(),
+            missing: HashMap::new(),
+            ttl,
         };
         fm.parent_map.insert(Fid::root(), None);
         fm
     }
 
     pub fn get_fid_by_fh(&self, fh: u64) -> Option<Fid> {
-        self.fhmap.get_by_left(&fh).cloned()
+        self.handles.get(&fh).map(|h| h.fid)
+    }
+
+    pub fn get_handle(&self, fh: u64) -> Option<&FileHandle> {
+        self.handles.get(&fh)
+    }
+
+    pub fn get_handle_mut(&mut self, fh: u64) -> Option<&mut FileHandle> {
+        self.handles.get_mut(&fh)
     }
 
     pub fn get_parent_fid(&self, fid: &Fid) -> Option<Option<Fid>> {
@@ -36,51 +156,334 @@ impl FidMap {
         self.listing_map.get(fid)
     }
 
+    // the item of fid as listed in its parent
+    pub fn get_item(&self, fid: &Fid) -> Option<RecListItem> {
+        match self.parent_map.get(fid)? {
+            Some(parent) => self
+                .listing_map
+                .get(parent)?
+                .children
+                .as_ref()?
+                .iter()
+                .find(|i| i.fid == *fid)
+                .cloned(),
+            None => Some(RecListItem::root()),
+        }
+    }
+
+    // fid, its parent, and so on up to the root
+    pub fn ancestors(&self, fid: &Fid) -> Vec<Fid> {
+        let mut fids = vec![*fid];
+        while let Some(Some(parent)) = self.parent_map.get(fids.last().unwrap()) {
+            fids.push(*parent);
+        }
+        fids
+    }
+
     pub fn get_listing_mut(&mut self, fid: Fid) -> &mut FidCachedList {
         self.listing_map.entry(fid).or_default()
     }
 
+    // replace the children of fid with a fresh listing from rec, keeping pending files in it
+    // fid becomes the parent of every child
+    // a pending file is hidden as soon as rec has a file with the same name (i.e. it is uploaded)
+    pub fn set_children(&mut self, fid: Fid, mut children: Vec<RecListItem>) -> Vec<RecListItem> {
+        for item in self.pending.values() {
+            if self.parent_map.get(&item.fid) == Some(&Some(fid))
+                && !children.iter().any(|c| c.name == item.name)
+            {
+                children.push(item.clone());
+            }
+        }
+        for child in children.iter() {
+            self.parent_map.insert(child.fid, Some(fid));
+        }
+        let listing = self.get_listing_mut(fid);
+        listing.children = Some(children.clone());
+        listing.listed = Some(Instant::now());
+        listing.restored = None;
+        children
+    }
+
+    // whether the children of fid are listed and recent enough to be trusted
+    pub fn is_fresh(&self, fid: &Fid) -> bool {
+        self.listing_map
+            .get(fid)
+            .and_then(|l| l.listed)
+            .is_some_and(|t| t.elapsed() < self.ttl.listing)
+    }
+
+    // whether the children of fid can be used without listing it first
+    // listings loaded from the metadata file are used until they are revalidated
+    pub fn is_usable(&self, fid: &Fid) -> bool {
+        self.is_fresh(fid)
+            || self
+                .listing_map
+                .get(fid)
+                .is_some_and(|l| l.restored.is_some())
+    }
+
+    pub fn is_restored(&self, fid: &Fid) -> bool {
+        !self.is_fresh(fid)
+            && self
+                .listing_map
+                .get(fid)
+                .is_some_and(|l| l.restored.is_some())
+    }
+
+    // whether name was recently looked up in parent on rec, and not found
+    pub fn is_missing(&self, parent: &Fid, name: &str) -> bool {
+        self.missing
+            .get(&(*parent, name.to_owned()))
+            .is_some_and(|t| t.elapsed() < self.ttl.negative)
+    }
+
+    // listed folders, most recently used first
+    pub fn listed_dirs(&self, limit: usize) -> Vec<Fid> {
+        let mut dirs: Vec<_> = self
+            .listing_map
+            .iter()
+            .filter(|(_, l)| l.children.is_some() && (l.listed.is_some() || l.restored.is_some()))
+            .map(|(fid, l)| (l.used, *fid))
+            .collect();
+        dirs.sort_by_key(|(used, _)| std::cmp::Reverse(*used));
+        dirs.into_iter().take(limit).map(|(_, fid)| fid).collect()
+    }
+
+    // drop the listing of a folder removed on rec, and of everything under it
+    pub fn forget(&mut self, fid: &Fid) {
+        if let Some(list) = self.listing_map.remove(fid) {
+            for child in list.children.unwrap_or_default() {
+                self.forget(&child.fid);
+            }
+        }
+    }
+
+    pub fn set_missing(&mut self, parent: &Fid, name: &str) {
+        if self.missing.len() >= 1024 {
+            let ttl = self.ttl.negative;
+            self.missing.retain(|_, t| t.elapsed() < ttl);
+        }
+        self.missing
+            .insert((*parent, name.to_owned()), Instant::now());
+    }
+
+    // show a created file in the listing of its parent until it is uploaded
+    pub fn add_pending(&mut self, parent: &Fid, item: RecListItem) {
+        self.parent_map.insert(item.fid, Some(*parent));
+        self.listing_map.insert(item.fid, FidCachedList::default());
+        if let Some(children) = self
+            .listing_map
+            .get_mut(parent)
+            .and_then(|l| l.children.as_mut())
+        {
+            children.push(item.clone());
+        }
+        self.pending.insert(item.fid, item);
+    }
+
+    // an open file is only hidden from its parent: its handles keep working until
+    // forget_unlinked() is called on the last release
+    pub fn remove_pending(&mut self, fid: &Fid) {
+        let item = match self.pending.remove(fid) {
+            Some(item) => item,
+            None => return,
+        };
+        if let Some(Some(parent)) = self.parent_map.get(fid) {
+            if let Some(children) = self
+                .listing_map
+                .get_mut(parent)
+                .and_then(|l| l.children.as_mut())
+            {
+                children.retain(|c| c.fid != *fid);
+            }
+        }
+        if self.has_handles(fid) {
+            self.unlinked.insert(*fid, item);
+        } else {
+            self.parent_map.remove(fid);
+            self.listing_map.remove(fid);
+        }
+    }
+
+    // the item of a pending file unlinked while open
+    pub fn get_unlinked(&self, fid: &Fid) -> Option<RecListItem> {
+        self.unlinked.get(fid).cloned()
+    }
+
+    // drop an unlinked file once it has no handles, true if fid was one
+    pub fn forget_unlinked(&mut self, fid: &Fid) -> bool {
+        if self.has_handles(fid) || self.unlinked.remove(fid).is_none() {
+            return false;
+        }
+        self.parent_map.remove(fid);
+        self.listing_map.remove(fid);
+        true
+    }
+
+    pub fn has_handles(&self, fid: &Fid) -> bool {
+        self.handles.values().any(|h| h.fid == *fid)
+    }
+
+    pub fn has_writers(&self, fid: &Fid) -> bool {
+        self.handles.values().any(|h| h.fid == *fid && h.writable())
+    }
+
+    // mark the writable handles of fid as written through, returning whether there is any
+    pub fn mark_writers_dirty(&mut self, fid: &Fid) -> bool {
+        let mut found = false;
+        for handle in self.handles.values_mut() {
+            if handle.fid == *fid && handle.writable() {
+                handle.dirty = true;
+                found = true;
+            }
+        }
+        found
+    }
+
+    // a pending file is uploaded: its handles and parent go to the new file on rec
+    pub fn replace_pending(&mut self, fid: &Fid, new: &Fid) {
+        if self.pending.remove(fid).is_none() {
+            return;
+        }
+        if let Some(Some(parent)) = self.parent_map.get(fid) {
+            if let Some(children) = self
+                .listing_map
+                .get_mut(parent)
+                .and_then(|l| l.children.as_mut())
+            {
+                children.retain(|c| c.fid != *fid);
+            }
+        }
+        self.replace_fid(fid, new);
+    }
+
     pub fn get_parentmap_mut(&mut self) -> &mut HashMap<Fid, Option<Fid>> {
         &mut self.parent_map
     }
 
-    // set the file handle for a Fid, and return the file handle
-    // it will not update maps if file handle exists
-    pub fn set_fh(&mut self, fid: &Fid, parent: Option<&Fid>, list: Option<&FidCachedList>) -> u64 {
-        match self.fhmap.get_by_right(fid) {
-            Some(&fh) => fh,
-            None => {
-                let fh = self.next_fh();
-                self.fhmap.insert(fh, *fid);
-                match list {
-                    Some(list) => {
-                        self.update_fid(fid, parent, list);
-                    }
-                    None => {
-                        assert!(
-                            (self.listing_map.contains_key(fid)
-                                && self.parent_map.contains_key(fid))
-                                || (fid.is_created())
-                        );
-                    }
-                };
-
-                fh
+    // allocate a new file handle for a Fid, and return the file handle
+    // every open() gets its own handle, even for the same Fid
+    pub fn open_fh(&mut self, fid: &Fid, flags: u32) -> u64 {
+        assert!(
+            (self.listing_map.contains_key(fid) && self.parent_map.contains_key(fid))
+                || fid.is_created()
+        );
+        let now = Some(Instant::now());
+        if let Some(Some(parent)) = self.parent_map.get(fid) {
+            if let Some(list) = self.listing_map.get_mut(parent) {
+                list.used = now;
             }
         }
+        if let Some(list) = self.listing_map.get_mut(fid) {
+            list.used = now;
+        }
+        // handles are never reused, so a stale fh cannot reach another file
+        self.last_fh += 1;
+        self.handles.insert(
+            self.last_fh,
+            FileHandle {
+                fid: *fid,
+                accmode: flags & libc::O_ACCMODE as u32,
+                file: None,
+                dirty: false,
+                pos: 0,
+            },
+        );
+        self.last_fh
+    }
+
+    pub fn release_fh(&mut self, fh: u64) -> Option<FileHandle> {
+        self.handles.remove(&fh)
     }
 
     pub fn update_fid(&mut self, fid: &Fid, parent: Option<&Fid>, list: &FidCachedList) {
-        self.listing_map.insert(*fid, list.clone());
+        let used = self.listing_map.get(fid).and_then(|l| l.used);
+        self.listing_map.insert(
+            *fid,
+            FidCachedList {
+                used,
+                ..list.clone()
+            },
+        );
         self.parent_map.insert(*fid, parent.cloned());
+        if let Some(children) = &list.children {
+            self.set_children(*fid, children.clone());
+        }
+    }
+
+    // a file has been replaced on rec by a new one: move its handles and parent to the new fid
+    pub fn replace_fid(&mut self, old: &Fid, new: &Fid) {
+        for handle in self.handles.values_mut().filter(|h| h.fid == *old) {
+            handle.fid = *new;
+        }
+        if let Some(parent) = self.parent_map.remove(old) {
+            self.parent_map.insert(*new, parent);
+        }
+        if let Some(list) = self.listing_map.remove(old) {
+            self.listing_map.insert(*new, list);
+        }
     }
 
-    fn next_fh(&self) -> u64 {
-        self.fhmap
+    // save the listings of folders, so that the next mount can use them before listing again
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let now = SystemTime::now();
+        let listings: Vec<_> = self
+            .listing_map
             .iter()
-            .enumerate()
-            .find(|(i, (&a, _))| (*i as u64) < a)
-            .map(|(i, _)| (i as u64) + 4)
-            .unwrap_or((self.fhmap.len() as u64) + 3)
+            .filter(|(fid, _)| !fid.is_created())
+            .filter_map(|(fid, l)| {
+                let listed = l.listed.map(|t| now - t.elapsed()).or(l.restored)?;
+                Some(SavedListing {
+                    fid: fid.to_string(),
+                    parent: self.parent_map.get(fid)?.as_ref().map(|p| p.to_string()),
+                    listed: unix_time(listed),
+                    children: l
+                        .children
+                        .as_ref()?
+                        .iter()
+                        .filter(|i| !i.fid.is_created())
+                        .map(SavedItem::from)
+                        .collect(),
+                })
+            })
+            .collect();
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&listings)?)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    // load the listings saved by save(), returning how many there are
+    // they are fresh while their TTL has not passed, and restored (see is_usable()) after that
+    pub fn load(&mut self, path: &Path) -> anyhow::Result<usize> {
+        let listings: Vec<SavedListing> = serde_json::from_slice(&std::fs::read(path)?)?;
+        let count = listings.len();
+        for saved in listings {
+            let fid: Fid = saved.fid.parse()?;
+            let parent = saved.parent.map(|p| p.parse()).transpose()?;
+            let children = saved
+                .children
+                .into_iter()
+                .map(RecListItem::try_from)
+                .collect::<Result<Vec<_>, _>>()?;
+            for child in children.iter() {
+                self.parent_map.entry(child.fid).or_insert(Some(fid));
+            }
+            let restored = SystemTime::UNIX_EPOCH + Duration::from_secs(saved.listed);
+            let age = restored.elapsed().unwrap_or_default();
+            self.parent_map.insert(fid, parent);
+            self.listing_map.insert(
+                fid,
+                FidCachedList {
+                    children: Some(children),
+                    listed: Instant::now()
+                        .checked_sub(age)
+                        .filter(|_| age < self.ttl.listing),
+                    used: None,
+                    restored: Some(restored),
+                },
+            );
+        }
+        Ok(count)
     }
 }
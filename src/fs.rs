@@ -1,74 +1,353 @@
-use crate::cache::Cache;
+use crate::cache::{self, Cache, CacheConfig, PendingUpload, UploadState};
 use crate::client::auth::{RecAuth, RecAuthMethod, Token};
+use crate::client::error::RecError;
 use crate::client::list::RecListItem;
 use crate::client::operation::Operation;
-use crate::client::RecClient;
+use crate::client::{RecClient, RecConfig, RetryPolicy};
 use crate::fid::Fid;
-use crate::fidmap::{FidCachedList, FidMap};
+use crate::fidmap::{FidCachedList, FidMap, Ttl};
+use crate::poll::{self, Poller, Refresher};
+use crate::prefetch::Prefetcher;
+use crate::upload::UploadQueue;
 use crate::Args;
 use fuse_mt::{
     CreatedEntry, DirectoryEntry, FileAttr, FileType, FilesystemMT, RequestInfo, ResultEntry,
-    ResultOpen, ResultReaddir, ResultStatfs, Statfs,
+    ResultOpen, ResultReaddir, ResultStatfs, ResultXattr, Statfs, Xattr,
 };
-use log::{debug, info, warn};
+use log::{debug, error, info, warn};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use std::borrow::{Borrow, BorrowMut};
 use std::ffi::{OsStr, OsString};
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::FileExt;
 use std::path::Path;
-use std::sync::{Arc, RwLock};
-use std::time::{Duration, SystemTime};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
 pub struct RecFs {
-    client: RecClient,
+    client: Arc<RecClient>,
     fid_map: Arc<RwLock<FidMap>>,
-    disk_cache: Cache,
-    fast_path: bool,
+    disk_cache: Arc<Cache>,
+    poller: Mutex<Option<Poller>>,
+    refresher: Mutex<Option<Refresher>>,
+    uploads: UploadQueue,
+    prefetcher: Prefetcher,
+    offline: Mutex<Offline>,
 }
 
 const BLOCK_SIZE: u32 = 512;
+// "1" to keep a file, or everything in a folder, downloaded
+const XATTR_PIN: &str = "user.recfs.pin";
+// "1" if a file, or everything listed in a folder, is downloaded (read-only)
+const XATTR_CACHED: &str = "user.recfs.cached";
+// what rec knows about a file or folder (read-only)
+const XATTR_FID: &str = "user.recfs.fid";
+const XATTR_HASH: &str = "user.recfs.hash";
+const XATTR_DISK: &str = "user.recfs.disk";
+const XATTR_DOWNLOAD_URL: &str = "user.recfs.download_url";
+// state of the background upload queue, on the root folder only (read-only)
+const XATTR_UPLOADS: &str = "user.recfs.uploads";
+const XATTRS: &[&str] = &[
+    XATTR_PIN,
+    XATTR_CACHED,
+    XATTR_FID,
+    XATTR_HASH,
+    XATTR_DISK,
+    XATTR_DOWNLOAD_URL,
+    XATTR_UPLOADS,
+];
+// how long rec is not tried again after it was found unreachable
+const OFFLINE_RETRY: Duration = Duration::from_secs(30);
+
+// rec is not used while offline: with --offline, or for a while after it was unreachable
+// folders and files are then served read-only from FidMap and the cache
+#[derive(Default)]
+struct Offline {
+    forced: bool,
+    since: Option<Instant>,
+}
 
 impl RecFs {
     pub fn new(args: &Args) -> Self {
-        let mut client = RecClient::default();
-        let mut auth = RecAuth::default();
+        let config = RecConfig {
+            api_url: args.api_url.clone(),
+            client_id: args.client_id.clone(),
+            signature: args.signature.clone(),
+            aes_key: RecConfig::parse_aes_key(&args.aes_key).expect("Invalid AES key"),
+            retry: RetryPolicy {
+                attempts: args.retries.max(1),
+                base_delay: Duration::from_millis(args.retry_delay),
+                ..Default::default()
+            },
+            upload_parallelism: args.upload_parallelism.max(1),
+            download_url_ttl: Duration::from_secs(args.download_url_ttl),
+        };
+        let mut client = RecClient::new(config);
+        let mut unreachable = None;
+        if !args.offline {
+            let mut auth = RecAuth::default();
 
-        if args.clear {
-            if let Err(e) = auth.clear_keyring() {
-                warn!("Failed to clear keyring: {}", e);
+            if args.clear {
+                if let Err(e) = auth.clear_keyring() {
+                    warn!("Failed to clear keyring: {}", e);
+                }
             }
-        }
 
-        if let Err(e) = auth.try_keyring() {
-            info!("Failed to get auth from keyring: {}", e);
-            info!("Try interactive login...");
-            let authdata = RecAuth::interactive();
-            match authdata {
-                RecAuthMethod::UsernamePassword(username, password) => {
-                    auth.login(&client, username, password).unwrap();
+            if let Err(e) = auth.try_keyring() {
+                info!("Failed to get auth from keyring: {}", e);
+                info!("Try interactive login...");
+                let authdata = RecAuth::interactive(&client);
+                match authdata {
+                    RecAuthMethod::UsernamePassword(username, password) => {
+                        auth.login(&client, username, password).unwrap();
+                    }
+                    RecAuthMethod::Cookie(access_token, refresh_token) => {
+                        auth.token = Some(Token {
+                            access_token,
+                            refresh_token,
+                        });
+                        auth.set_keyring().unwrap();
+                    }
                 }
-                RecAuthMethod::Cookie(access_token, refresh_token) => {
-                    auth.token = Some(Token {
-                        access_token,
-                        refresh_token,
-                    });
-                    auth.set_keyring().unwrap();
+            }
+            client.set_auth(auth);
+
+            // test if it is a valid auth
+            match client.stat() {
+                // files kept in --cache-dir can still be served
+                Err(e) if e.is_unreachable() && args.cache_dir.is_some() => unreachable = Some(e),
+                result => {
+                    result.expect("Failed to stat root directory. If you see this message, please run `recfs --clear` to clear keyring item.");
                 }
             }
         }
-        client.set_auth(auth);
 
-        // test if it is a valid auth
-        let result = client.stat();
-        result.expect("Failed to stat root directory. If you see this message, please run `recfs --clear` to clear keyring item.");
+        let cache = Cache::new(
+            args.cache_dir.clone().unwrap_or_else(Cache::temp_path),
+            CacheConfig {
+                max_size: args.cache_size << 20,
+                persistent: args.cache_dir.is_some(),
+                prefetch_files: args.prefetch_files,
+                prefetch_size: args.prefetch_size << 20,
+                connections: args.download_connections,
+                min_split: args.min_split_size << 20,
+                ..Default::default()
+            },
+        )
+        .expect("Failed to open the cache folder");
+        let ttl = Ttl {
+            listing: Duration::from_secs(args.listing_ttl),
+            negative: Duration::from_secs(args.negative_ttl),
+        };
+        let fs = Self::with_client(client, cache, ttl, args.upload_workers);
+        if args.offline {
+            fs.set_offline();
+        } else if let Some(e) = unreachable {
+            fs.check_unreachable(&e);
+        }
+        if let Some(interval) = args.poll_interval.filter(|_| !args.offline) {
+            fs.start_poller(Duration::from_secs(interval));
+        }
+        let pinned = fs.disk_cache.pinned();
+        if !pinned.is_empty() && !fs.is_offline() {
+            fs.sync_pinned(pinned);
+        }
+        let pending = fs.disk_cache.pending_uploads();
+        if !pending.is_empty() && !fs.is_offline() {
+            if ask_recover(&pending) {
+                fs.recover_uploads();
+            } else {
+                for p in pending {
+                    fs.disk_cache.discard_created(p.fid);
+                }
+            }
+        }
+        fs
+    }
 
-        Self {
+    pub fn with_client(
+        client: RecClient,
+        disk_cache: Cache,
+        ttl: Ttl,
+        upload_workers: usize,
+    ) -> Self {
+        let client = Arc::new(client);
+        let fid_map = Arc::new(RwLock::new(FidMap::new(ttl)));
+        let disk_cache = Arc::new(disk_cache);
+        let uploads = UploadQueue::start(
+            upload_workers,
+            client.clone(),
+            fid_map.clone(),
+            disk_cache.clone(),
+        );
+        let prefetcher = Prefetcher::start(client.clone(), disk_cache.clone());
+        let fs = Self {
             client,
-            fid_map: Arc::new(RwLock::new(FidMap::new())),
-            disk_cache: Cache::default(),
-            fast_path: !args.no_fast_path,
+            fid_map,
+            disk_cache,
+            poller: Mutex::new(None),
+            refresher: Mutex::new(None),
+            uploads,
+            prefetcher,
+            offline: Mutex::new(Offline::default()),
+        };
+        if let Some(path) = fs.disk_cache.metadata_path() {
+            if path.exists() {
+                match fs.fid_map.write().unwrap().load(&path) {
+                    Ok(count) => info!("Loaded {} folder listings from {}", count, path.display()),
+                    Err(e) => warn!("Failed to load {}: {}", path.display(), e),
+                }
+            }
+            *fs.refresher.lock().unwrap() = Some(Refresher::spawn(
+                path,
+                fs.client.clone(),
+                fs.fid_map.clone(),
+                fs.disk_cache.clone(),
+            ));
+        }
+        fs
+    }
+
+    pub fn start_poller(&self, interval: Duration) {
+        let poller = Poller::spawn(
+            interval,
+            self.client.clone(),
+            self.fid_map.clone(),
+            self.disk_cache.clone(),
+        );
+        if let Some(old) = self.poller.lock().unwrap().replace(poller) {
+            old.stop();
+        }
+    }
+
+    // never connect to rec, as with --offline
+    pub fn set_offline(&self) {
+        self.offline.lock().unwrap().forced = true;
+    }
+
+    fn is_offline(&self) -> bool {
+        let offline = self.offline.lock().unwrap();
+        offline.forced || offline.since.is_some_and(|t| t.elapsed() < OFFLINE_RETRY)
+    }
+
+    // go offline for a while if e means that rec cannot be reached, returning whether it does
+    fn check_unreachable(&self, e: &RecError) -> bool {
+        if !e.is_unreachable() {
+            return false;
+        }
+        let mut offline = self.offline.lock().unwrap();
+        if offline.since.is_none_or(|t| t.elapsed() >= OFFLINE_RETRY) {
+            warn!(
+                "rec is unreachable ({}), serving the cache read-only for {:?}",
+                e, OFFLINE_RETRY
+            );
+        }
+        offline.since = Some(Instant::now());
+        true
+    }
+
+    // modifications need rec
+    fn check_online(&self) -> Result<(), libc::c_int> {
+        if self.is_offline() {
+            Err(libc::EROFS)
+        } else {
+            Ok(())
+        }
+    }
+
+    // revalidate a listing loaded from the metadata file in background
+    fn refresh(&self, fid: Fid) {
+        if self.is_offline() {
+            return;
+        }
+        if let Some(refresher) = self.refresher.lock().unwrap().as_ref() {
+            refresher.push(fid);
+        }
+    }
+
+    // upload the created files left by a previous mount which did not finish uploading them
+    // a file which reached rec before the crash is only removed from the cache
+    pub fn recover_uploads(&self) {
+        for pending in self.disk_cache.pending_uploads() {
+            let path = self.disk_cache.get_created_path(pending.fid);
+            let metadata = match std::fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    warn!("Failed to recover {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            if pending.state == UploadState::Uploading && self.is_uploaded(&pending, &path) {
+                info!("{} was uploaded by the last mount", pending.name);
+                self.disk_cache.forget_created(pending.fid);
+                continue;
+            }
+            info!("Upload {} left by the last mount", pending.name);
+            self.fid_map.write().unwrap().add_pending(
+                &pending.parent,
+                RecListItem {
+                    bytes: metadata.len() as usize,
+                    name: pending.name,
+                    hash: None,
+                    fid: pending.fid,
+                    ftype: FileType::RegularFile,
+                    time_updated: metadata.modified().unwrap_or_else(|_| SystemTime::now()),
+                },
+            );
+            self.uploads.push(pending.fid);
         }
     }
+
+    // whether rec has a file with the same name and content in the same folder
+    fn is_uploaded(&self, pending: &PendingUpload, path: &Path) -> bool {
+        let items = match self.req_update_listing(pending.parent) {
+            Ok(listing) => listing.children.unwrap_or_default(),
+            Err(_) => return false,
+        };
+        let hash = match cache::file_md5(path) {
+            Ok(hash) => hash,
+            Err(_) => return false,
+        };
+        items
+            .iter()
+            .any(|i| i.name == pending.name && i.hash.as_ref() == Some(&hash))
+    }
+
+    // look for changes on rec once, as the poller does in background
+    #[cfg(test)]
+    pub fn poll(&self) -> crate::poll::Summary {
+        crate::poll::poll(&self.client, &self.fid_map, &self.disk_cache)
+    }
+
+    #[cfg(test)]
+    pub fn wait_uploads(&self) {
+        self.uploads.wait_all();
+    }
+
+    #[cfg(test)]
+    pub fn wait_prefetch(&self) {
+        self.prefetcher.wait_all();
+    }
+}
+
+// whether to upload the files left by a previous mount, or move them to lost+found
+fn ask_recover(pending: &[PendingUpload]) -> bool {
+    println!("Files created by the last mount which are not uploaded yet:");
+    for p in pending {
+        println!("  {} (in {})", p.name, p.parent);
+    }
+    print!("Upload them now? They are moved to lost+found otherwise. [Y/n] ");
+    std::io::stdout().flush().unwrap();
+    let mut input = String::new();
+    let _ = std::io::stdin().read_line(&mut input);
+    !input.trim().eq_ignore_ascii_case("n")
+}
+
+// log a failed rec request and convert it to the errno returned to FUSE
+fn rec_errno(e: RecError) -> libc::c_int {
+    warn!("rec request failed: {}", e);
+    e.errno()
 }
 
 impl From<RecListItem> for FileAttr {
@@ -92,6 +371,26 @@ impl From<RecListItem> for FileAttr {
 }
 
 impl FilesystemMT for RecFs {
+    fn destroy(&self) {
+        if let Some(poller) = self.poller.lock().unwrap().take() {
+            poller.stop();
+        }
+        self.prefetcher.stop();
+        // queued files are uploaded before unmounting
+        self.uploads.stop();
+        // not holding the lock while the refresher finishes, as refresh() is called with FidMap locked
+        let refresher = self.refresher.lock().unwrap().take();
+        if let Some(refresher) = refresher {
+            refresher.stop();
+        }
+        if let Some(path) = self.disk_cache.metadata_path() {
+            if let Err(e) = self.fid_map.read().unwrap().save(&path) {
+                warn!("Failed to save {}: {}", path.display(), e);
+            }
+        }
+        self.disk_cache.cleanup();
+    }
+
     fn getattr(&self, _req: RequestInfo, path: &Path, fh: Option<u64>) -> ResultEntry {
         let (fid, parent) = if let Some(fh) = fh {
             self.get_fid_with_parent(fh)?
@@ -99,11 +398,19 @@ impl FilesystemMT for RecFs {
             self.req_fid(path)?
         };
         let item = self.get_item(fid, parent)?;
-        let attr: FileAttr = item.into();
+        let mut attr: FileAttr = item.into();
+        if fid.is_created() || self.disk_cache.is_dirty(fid) {
+            // the local copy is newer than the listing
+            let path = self.disk_cache.contains(fid).ok_or(libc::EIO)?;
+            let metadata = std::fs::metadata(path).map_err(|_| libc::EIO)?;
+            attr.size = metadata.len();
+            attr.blocks = metadata.len() / BLOCK_SIZE as u64;
+            attr.mtime = metadata.modified().unwrap_or(attr.mtime);
+        }
         Ok((Duration::new(1, 0), attr))
     }
 
-    fn opendir(&self, _req: RequestInfo, path: &Path, _flags: u32) -> ResultOpen {
+    fn opendir(&self, _req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
         let (fid, parent) = self.req_fid(path)?;
         debug!("opendir(): {} {:?}", fid, parent);
         let item = self.get_item(fid, parent)?;
@@ -111,25 +418,46 @@ impl FilesystemMT for RecFs {
         if item.ftype != FileType::Directory {
             return Err(libc::ENOTDIR);
         }
+        self.prefetcher.opened_dir(fid);
         Ok((
             self.fid_map
                 .write()
                 .unwrap()
                 .borrow_mut()
-                .set_fh(&fid, parent.as_ref(), None),
+                .open_fh(&fid, flags),
             0,
         ))
     }
 
+    fn releasedir(
+        &self,
+        _req: RequestInfo,
+        _path: &Path,
+        fh: u64,
+        _flags: u32,
+    ) -> fuse_mt::ResultEmpty {
+        self.fid_map
+            .write()
+            .unwrap()
+            .release_fh(fh)
+            .map(|_| ())
+            .ok_or(libc::EBADF)
+    }
+
     // readdir only reads the "cache" generated by opendir()
     fn readdir(&self, _req: RequestInfo, _path: &Path, fh: u64) -> ResultReaddir {
         // let fid = self.get_fid(fh)?;
         // let items = self.client.list(fid.clone()).map_err(|_| libc::ENOENT)?;
         let (fid, _parent) = self.get_fid_with_parent(fh)?;
         let listing = self.get_listing(fid)?;
+        let not_listed = if self.is_offline() {
+            libc::EIO
+        } else {
+            libc::ENOTDIR
+        };
         Ok(listing
             .children
-            .ok_or(libc::ENOTDIR)?
+            .ok_or(not_listed)?
             .iter()
             .map(|i| DirectoryEntry {
                 name: OsString::from(i.name.clone()),
@@ -139,7 +467,20 @@ impl FilesystemMT for RecFs {
     }
 
     fn statfs(&self, _req: RequestInfo, _path: &Path) -> ResultStatfs {
-        let userinfo = self.client.stat().map_err(|_| libc::ENOENT)?;
+        if self.is_offline() {
+            // nothing is known about the space on rec
+            return Ok(Statfs {
+                blocks: 0,
+                bfree: 0,
+                bavail: 0,
+                files: 0,
+                ffree: 0,
+                bsize: BLOCK_SIZE,
+                namelen: 255,
+                frsize: BLOCK_SIZE,
+            });
+        }
+        let userinfo = self.client.stat().map_err(rec_errno)?;
         info!("statfs: {:?}", userinfo);
         Ok(Statfs {
             blocks: userinfo.total_space / BLOCK_SIZE as u64,
@@ -161,10 +502,11 @@ impl FilesystemMT for RecFs {
         _mode: u32,
         flags: u32,
     ) -> fuse_mt::ResultCreate {
+        self.check_online()?;
         if self.req_fid(&parent.join(name)).is_ok() {
             return Err(libc::EEXIST);
         }
-        let (fid, parent_fid) = self.create_in_cache(parent, name)?;
+        let (fid, _parent_fid) = self.create_in_cache(parent, name)?;
 
         Ok(CreatedEntry {
             ttl: Duration::new(1, 0),
@@ -188,12 +530,17 @@ impl FilesystemMT for RecFs {
                 .write()
                 .unwrap()
                 .borrow_mut()
-                .set_fh(&fid, Some(&parent_fid), None),
+                .open_fh(&fid, flags),
             flags,
         })
     }
 
     fn open(&self, _req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
+        if flags & libc::O_ACCMODE as u32 != libc::O_RDONLY as u32
+            || flags & (libc::O_CREAT | libc::O_TRUNC) as u32 != 0
+        {
+            self.check_online()?;
+        }
         let fid_res = self.req_fid(path);
         let (fid, parent) = match fid_res {
             Ok((fid, parent)) => (fid, parent),
@@ -209,21 +556,43 @@ impl FilesystemMT for RecFs {
                 (fid, Some(parent_fid))
             }
         };
+        let mut truncated = false;
         if !fid.is_created() {
             let item = self.get_item(fid, parent)?;
             if item.ftype != FileType::RegularFile {
                 return Err(libc::EISDIR);
             }
+            // writing to a remote file: edit a local copy, which is uploaded on release()
+            if flags & libc::O_ACCMODE as u32 != libc::O_RDONLY as u32 {
+                if flags & libc::O_TRUNC as u32 != 0 {
+                    // the old content is not needed at all
+                    self.disk_cache.truncate(fid).map_err(|_| libc::EIO)?;
+                    truncated = true;
+                } else {
+                    self.ensure_cached(fid)?;
+                }
+            } else if let Some(parent) = parent.filter(|_| !self.is_offline()) {
+                let children = self.get_listing(parent).ok().and_then(|l| l.children);
+                if let Some(children) = children {
+                    self.prefetcher.opened(parent, fid, &children);
+                }
+            }
+        } else if flags & libc::O_ACCMODE as u32 != libc::O_RDONLY as u32
+            && flags & libc::O_TRUNC as u32 != 0
+        {
+            // reopening a pending file, which is still local only
+            File::create(self.disk_cache.get_created_path(fid)).map_err(|_| libc::EIO)?;
         }
 
-        Ok((
-            self.fid_map
-                .write()
-                .unwrap()
-                .borrow_mut()
-                .set_fh(&fid, parent.as_ref(), None),
-            flags,
-        ))
+        let mut map = self.fid_map.write().unwrap();
+        let fh = map.open_fh(&fid, flags);
+        if truncated {
+            // so that release() writes it back even if nothing is written
+            if let Some(handle) = map.get_handle_mut(fh) {
+                handle.dirty = true;
+            }
+        }
+        Ok((fh, flags))
     }
 
     fn read(
@@ -235,61 +604,10 @@ impl FilesystemMT for RecFs {
         size: u32,
         callback: impl FnOnce(fuse_mt::ResultSlice<'_>) -> fuse_mt::CallbackResult,
     ) -> fuse_mt::CallbackResult {
-        let fid = match self.get_fid(fh) {
-            Err(e) => {
-                warn!("read() failed when getting fid: {}", e);
-                return callback(Err(libc::EIO));
-            }
-            Ok(res) => res,
-        };
-
-        let mut file = match {
-            // created file are always contained in disk_cache
-            match self.disk_cache.contains(fid) {
-                Some(path) => File::open(path),
-                None => {
-                    let url = self.client.get_download_url(fid);
-                    let url = match url {
-                        Ok(url) => url,
-                        Err(e) => {
-                            warn!("read() failed when getting URL: {}", e);
-                            return callback(Err(libc::EIO));
-                        }
-                    };
-                    if let Err(e) = self.disk_cache.fetch(fid, url) {
-                        warn!("read() failed when downloading: {}", e);
-                        return callback(Err(libc::EIO));
-                    }
-                    let path = match self.disk_cache.contains(fid) {
-                        Some(path) => path,
-                        None => {
-                            warn!("read() failed when getting path after downloaded");
-                            return callback(Err(libc::EIO));
-                        }
-                    };
-                    File::open(path)
-                }
-            }
-        } {
-            Ok(file) => file,
-            Err(e) => {
-                warn!("read() failed when opening cached file: {}", e);
-                return callback(Err(libc::EIO));
-            }
-        };
-        if let Err(e) = file.seek(SeekFrom::Start(offset)) {
-            warn!("read() failed when seeking cached file: {}", e);
-            return callback(Err(libc::EIO));
+        match self.read_data(fh, offset, size) {
+            Ok(data) => callback(Ok(&data)),
+            Err(e) => callback(Err(e)),
         }
-
-        let mut data = Vec::<u8>::with_capacity(size as usize);
-        let mut chunk = file.take(size as u64);
-        if let Err(e) = chunk.read_to_end(&mut data) {
-            warn!("read() failed when reading cached file: {}", e);
-            return callback(Err(libc::EIO));
-        }
-
-        callback(Ok(&data))
     }
 
     fn write(
@@ -299,31 +617,70 @@ impl FilesystemMT for RecFs {
         fh: u64,
         offset: u64,
         data: Vec<u8>,
-        flags: u32,
+        _flags: u32,
     ) -> fuse_mt::ResultWrite {
-        if flags & libc::O_RDONLY as u32 != 0 {
-            return Err(libc::EBADF);
+        let (fid, file) = self.handle_file(fh, true)?;
+        file.write_all_at(&data, offset).map_err(|e| {
+            warn!("write() failed when writing cached file: {}", e);
+            libc::EIO
+        })?;
+        if !fid.is_created() {
+            self.disk_cache.mark_dirty(fid);
+        }
+        if let Some(handle) = self.fid_map.write().unwrap().get_handle_mut(fh) {
+            handle.dirty = true;
+            handle.pos = offset + data.len() as u64;
         }
 
-        let fid = self.get_fid(fh)?;
+        Ok(data.len() as u32)
+    }
 
-        let mut file = match self.disk_cache.contains(fid) {
-            Some(path) => File::create(path),
+    fn truncate(
+        &self,
+        _req: RequestInfo,
+        path: &Path,
+        fh: Option<u64>,
+        size: u64,
+    ) -> fuse_mt::ResultEmpty {
+        self.check_online()?;
+        let fid = match fh {
+            Some(fh) => {
+                let (fid, file) = self.handle_file(fh, true)?;
+                file.set_len(size).map_err(|_| libc::EIO)?;
+                if let Some(handle) = self.fid_map.write().unwrap().get_handle_mut(fh) {
+                    handle.dirty = true;
+                }
+                fid
+            }
             None => {
-                let url = self.client.get_download_url(fid).map_err(|_| libc::EIO)?;
-
-                self.disk_cache.fetch(fid, url).map_err(|_| libc::EIO)?;
-                let path = self.disk_cache.contains(fid).ok_or(libc::EIO)?;
-                File::create(path)
+                let (fid, parent) = self.req_fid(path)?;
+                if self.get_item(fid, parent)?.ftype != FileType::RegularFile {
+                    return Err(libc::EISDIR);
+                }
+                if size == 0 && !fid.is_created() {
+                    self.disk_cache.truncate(fid).map_err(|_| libc::EIO)?;
+                } else {
+                    let path = self.ensure_cached(fid)?;
+                    let file = OpenOptions::new()
+                        .write(true)
+                        .open(path)
+                        .map_err(|_| libc::EIO)?;
+                    file.set_len(size).map_err(|_| libc::EIO)?;
+                }
+                fid
+            }
+        };
+        if !fid.is_created() {
+            self.disk_cache.mark_dirty(fid);
+            // open(O_TRUNC) comes as an open() followed by truncate() without fh too:
+            // its writable handles write it back when released, with the data written after
+            let writers = fh.is_none() && self.fid_map.write().unwrap().mark_writers_dirty(&fid);
+            // truncate(2) of a file not open for writing: nobody will release() it
+            if fh.is_none() && !writers {
+                self.write_back(fid)?;
             }
         }
-        .map_err(|_| libc::EIO)?;
-
-        file.seek(SeekFrom::Start(offset)).map_err(|_| libc::EIO)?;
-
-        let bytes = file.write(&data).map_err(|_| libc::EIO)?;
-
-        Ok(bytes as u32)
+        Ok(())
     }
 
     fn mkdir(
@@ -333,10 +690,11 @@ impl FilesystemMT for RecFs {
         name: &std::ffi::OsStr,
         _mode: u32,
     ) -> ResultEntry {
+        self.check_online()?;
         let (fid, _parent) = self.req_fid(parent)?;
         self.client
             .mkdir(fid, name.to_str().ok_or(libc::EINVAL)?.to_string())
-            .map_err(|_| libc::EIO)?;
+            .map_err(rec_errno)?;
         let list = self.req_update_listing(fid)?;
         let mut found = None;
         let children = list.children.ok_or(libc::ENOTDIR)?;
@@ -360,6 +718,7 @@ impl FilesystemMT for RecFs {
         parent: &Path,
         name: &std::ffi::OsStr,
     ) -> fuse_mt::ResultEmpty {
+        self.check_online()?;
         self.delete(parent, name)
     }
 
@@ -369,6 +728,13 @@ impl FilesystemMT for RecFs {
         parent: &Path,
         name: &std::ffi::OsStr,
     ) -> fuse_mt::ResultEmpty {
+        self.check_online()?;
+        // rec happily recycles non-empty folders, but rmdir(2) must not
+        let (fid, _parent) = self.req_fid(&parent.join(name))?;
+        let listing = self.req_update_listing(fid)?;
+        if !listing.children.ok_or(libc::ENOTDIR)?.is_empty() {
+            return Err(libc::ENOTEMPTY);
+        }
         self.delete(parent, name)
     }
 
@@ -380,6 +746,7 @@ impl FilesystemMT for RecFs {
         newparent: &Path,
         newname: &std::ffi::OsStr,
     ) -> fuse_mt::ResultEmpty {
+        self.check_online()?;
         if name == newname {
             // move operation
             if parent == newparent {
@@ -392,7 +759,7 @@ impl FilesystemMT for RecFs {
             let (newfid, _newparent) = self.req_fid(newparent)?;
             self.client
                 .operation(Operation::Move, fid, item.ftype, Some(newfid.to_string()))
-                .map_err(|_| libc::EIO)?;
+                .map_err(rec_errno)?;
             self.req_update_listing(parent.unwrap_or_else(Fid::root))?;
             self.req_update_listing(newfid)?;
             Ok(())
@@ -405,12 +772,12 @@ impl FilesystemMT for RecFs {
                 FileType::Directory => {
                     self.client
                         .rename(fid, newname.to_string(), item.ftype)
-                        .map_err(|_| libc::EIO)?;
+                        .map_err(rec_errno)?;
                 }
                 FileType::RegularFile => {
                     self.client
                         .rename_ext(fid, newname.to_string())
-                        .map_err(|_| libc::EIO)?;
+                        .map_err(rec_errno)?;
                 }
                 _ => {
                     warn!("rename() does not support renaming other file types");
@@ -442,6 +809,7 @@ impl FilesystemMT for RecFs {
             "link() path: {:?}, newparent: {:?}, newname: {:?}",
             path, newparent, newname
         );
+        self.check_online()?;
         if newname != path.file_name().ok_or(libc::EINVAL)? {
             warn!("symlink() does not support when name != target.file_name()");
             return Err(libc::ENOSYS);
@@ -456,7 +824,7 @@ impl FilesystemMT for RecFs {
                 from_item.ftype,
                 Some(to_fid.to_string()),
             )
-            .map_err(|_| libc::EIO)?;
+            .map_err(rec_errno)?;
 
         let mut retry = 0;
         let mut found = None;
@@ -490,46 +858,492 @@ impl FilesystemMT for RecFs {
         _req: RequestInfo,
         _path: &Path,
         fh: u64,
-        flags: u32,
+        _flags: u32,
         _lock_owner: u64,
         _flush: bool,
     ) -> fuse_mt::ResultEmpty {
-        if flags & libc::O_RDONLY as u32 != 0 {
+        let (handle, last, unlinked) = {
+            let mut map = self.fid_map.write().unwrap();
+            let handle = map.release_fh(fh).ok_or(libc::EBADF)?;
+            let last = !map.has_handles(&handle.fid);
+            let unlinked = map.forget_unlinked(&handle.fid);
+            (handle, last, unlinked)
+        };
+        let fid = handle.fid;
+        if handle.file.is_some() {
+            self.disk_cache.unhold(fid);
+        }
+        // the file may be evicted now
+        self.evict_cache();
+        if fid.is_created() {
+            if unlinked {
+                self.disk_cache.forget_created(fid);
+            } else if last {
+                // a new file is uploaded in background when its last handle is closed
+                self.uploads.push(fid);
+            }
             Ok(())
+        } else if handle.dirty && self.disk_cache.is_dirty(fid) {
+            self.write_back(fid).map(|_| ())
         } else {
-            let fid = self.get_fid(fh)?;
-            if fid.is_created() {
-                let (parent, filename) = self.disk_cache.pop_created_info(fid).ok_or(libc::EIO)?;
-                let filepath = self.disk_cache.get_created_path(fid);
-                self.client
-                    .upload(parent, &filepath, filename)
-                    .map_err(|e| {
-                        // most programs ignore the return value of close()
-                        // so here warn! to notify users of uploading failure
-                        warn!("release() upload failed with: {}", e);
-                        libc::EIO
-                    })?;
-                self.req_update_listing(parent)?;
+            Ok(())
+        }
+    }
+
+    fn fsync(
+        &self,
+        _req: RequestInfo,
+        _path: &Path,
+        fh: u64,
+        _datasync: bool,
+    ) -> fuse_mt::ResultEmpty {
+        let fid = self.get_fid(fh)?;
+        if fid.is_created() {
+            self.check_online()?;
+            // then it is a file on rec, whose later changes are written back on release
+            self.uploads.upload_open(fid)?;
+        } else if self.disk_cache.is_dirty(fid) {
+            self.write_back(fid)?;
+        }
+        Ok(())
+    }
+
+    fn getxattr(&self, _req: RequestInfo, path: &Path, name: &OsStr, size: u32) -> ResultXattr {
+        let (fid, parent) = self.req_fid(path)?;
+        let item = self.get_item(fid, parent)?;
+        let name = name.to_str().ok_or(libc::ENODATA)?;
+        if !self.xattr_names(&item).contains(&name) {
+            return Err(libc::ENODATA);
+        }
+        let flag = |b: bool| if b { "1" } else { "0" }.to_owned();
+        let value = match name {
+            XATTR_PIN => flag(self.is_pinned(fid)),
+            XATTR_CACHED => flag(self.is_cached(fid)),
+            XATTR_FID => fid.to_string(),
+            XATTR_HASH => item.hash.ok_or(libc::ENODATA)?,
+            XATTR_DISK => {
+                let ancestors = self.fid_map.read().unwrap().ancestors(&fid);
+                let disk = ancestors.iter().find_map(|f| f.root_disk());
+                disk.ok_or(libc::ENODATA)?.to_owned()
+            }
+            XATTR_DOWNLOAD_URL => self.client.get_download_url(fid).map_err(rec_errno)?,
+            XATTR_UPLOADS => self.uploads.status(),
+            _ => return Err(libc::ENODATA),
+        };
+        xattr_reply(value.into_bytes(), size)
+    }
+
+    fn listxattr(&self, _req: RequestInfo, path: &Path, size: u32) -> ResultXattr {
+        let (fid, parent) = self.req_fid(path)?;
+        let item = self.get_item(fid, parent)?;
+        let mut names = Vec::new();
+        for name in self.xattr_names(&item) {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        xattr_reply(names, size)
+    }
+
+    fn setxattr(
+        &self,
+        _req: RequestInfo,
+        path: &Path,
+        name: &OsStr,
+        value: &[u8],
+        _flags: u32,
+        _position: u32,
+    ) -> fuse_mt::ResultEmpty {
+        let (fid, _parent) = self.req_fid(path)?;
+        match name.to_str() {
+            Some(XATTR_PIN) => match value.trim_ascii() {
+                b"1" => {
+                    self.disk_cache.pin(fid, true);
+                    if !self.is_offline() {
+                        self.sync_pinned(vec![fid]);
+                    }
+                    Ok(())
+                }
+                b"0" => {
+                    self.disk_cache.pin(fid, false);
+                    Ok(())
+                }
+                _ => Err(libc::EINVAL),
+            },
+            Some(name) if XATTRS.contains(&name) => Err(libc::EPERM),
+            _ => Err(libc::ENOTSUP),
+        }
+    }
+
+    fn removexattr(&self, _req: RequestInfo, path: &Path, name: &OsStr) -> fuse_mt::ResultEmpty {
+        let (fid, _parent) = self.req_fid(path)?;
+        match name.to_str() {
+            Some(XATTR_PIN) => {
+                self.disk_cache.pin(fid, false);
                 Ok(())
-            } else {
-                // not implemented
-                Err(libc::ENOSYS)
             }
+            Some(name) if XATTRS.contains(&name) => Err(libc::EPERM),
+            _ => Err(libc::ENODATA),
         }
     }
 }
 
+// the size only when size is 0, as getxattr(2) and listxattr(2) do
+fn xattr_reply(data: Vec<u8>, size: u32) -> ResultXattr {
+    if size == 0 {
+        Ok(Xattr::Size(data.len() as u32))
+    } else if (size as usize) < data.len() {
+        Err(libc::ERANGE)
+    } else {
+        Ok(Xattr::Data(data))
+    }
+}
+
 impl RecFs {
+    pub fn read_data(&self, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>, libc::c_int> {
+        let (fid, pos) = {
+            let map = self.fid_map.read().unwrap();
+            let handle = map.get_handle(fh).ok_or(libc::EBADF)?;
+            if !handle.readable() {
+                return Err(libc::EBADF);
+            }
+            (handle.fid, handle.pos)
+        };
+        let item = if fid.is_created() {
+            None
+        } else {
+            let item = self.remote_item(fid)?;
+            self.disk_cache.validate(&item);
+            Some(item)
+        };
+        // a remote file which is not completely local: only download the blocks being read
+        if let Some(item) = item.filter(|_| self.disk_cache.contains(fid).is_none()) {
+            if self.is_offline() {
+                if !self.disk_cache.has_range(fid, offset, size as u64) {
+                    return Err(libc::EIO);
+                }
+                self.disk_cache.touch(fid);
+                return self.read_local(fh, offset, size);
+            }
+            self.disk_cache
+                .fetch_range(&self.client, &item, offset, size as u64)
+                .map_err(|e| {
+                    warn!("Failed to download {}: {}", fid, e);
+                    if self.check_unreachable(&e) {
+                        libc::EIO
+                    } else {
+                        e.errno()
+                    }
+                })?;
+            // sequential read: fetch what comes next in the background
+            if offset == pos {
+                self.disk_cache.prefetch(
+                    self.client.clone(),
+                    &item,
+                    offset + size as u64,
+                    self.disk_cache.config().readahead,
+                );
+            }
+            self.evict_cache();
+        } else {
+            self.disk_cache.touch(fid);
+        }
+        self.read_local(fh, offset, size)
+    }
+
+    // read from the local copy behind fh, which holds the bytes being read
+    fn read_local(&self, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>, libc::c_int> {
+        let (_fid, file) = self.handle_file(fh, false)?;
+
+        let len = file.metadata().map_err(|_| libc::EIO)?.len();
+        let size = (size as u64).min(len.saturating_sub(offset));
+
+        let mut data = vec![0; size as usize];
+        let mut len = 0;
+        while len < data.len() {
+            match file.read_at(&mut data[len..], offset + len as u64) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    warn!("read() failed when reading cached file: {}", e);
+                    return Err(libc::EIO);
+                }
+            }
+        }
+        data.truncate(len);
+        if let Some(handle) = self.fid_map.write().unwrap().get_handle_mut(fh) {
+            handle.pos = offset + len as u64;
+        }
+
+        Ok(data)
+    }
+
+    // the open local copy behind a file handle, opened on first use
+    // writable handles download the whole file, read-only ones may get a partial copy
+    fn handle_file(&self, fh: u64, write: bool) -> Result<(Fid, Arc<File>), libc::c_int> {
+        let (fid, writable) = {
+            let map = self.fid_map.read().unwrap();
+            let handle = map.get_handle(fh).ok_or(libc::EBADF)?;
+            if (write && !handle.writable()) || (!write && !handle.readable()) {
+                return Err(libc::EBADF);
+            }
+            if let Some(file) = &handle.file {
+                return Ok((handle.fid, file.clone()));
+            }
+            (handle.fid, handle.writable())
+        };
+
+        let path = if writable {
+            Some(self.ensure_cached(fid)?)
+        } else {
+            None
+        };
+        // so that validate() leaves the local copy alone until the handle is released
+        self.disk_cache.hold(fid);
+        let file = match path {
+            Some(path) => OpenOptions::new().read(true).write(true).open(path),
+            None => self.disk_cache.open(fid),
+        };
+        let mut map = self.fid_map.write().unwrap();
+        let handle = match (file, map.get_handle_mut(fh)) {
+            (Ok(file), Some(handle)) if handle.file.is_none() => {
+                handle.file = Some(Arc::new(file));
+                handle
+            }
+            (file, handle) => {
+                self.disk_cache.unhold(fid);
+                if let Err(e) = file {
+                    warn!("Failed to open cached file of {}: {}", fid, e);
+                    return Err(libc::EIO);
+                }
+                // opened by another thread meanwhile
+                handle.ok_or(libc::EBADF)?
+            }
+        };
+        Ok((handle.fid, handle.file.clone().unwrap()))
+    }
+
+    // path of the local copy of fid, downloading it if needed
+    fn ensure_cached(&self, fid: Fid) -> Result<String, libc::c_int> {
+        // created file are always contained in disk_cache
+        if fid.is_created() {
+            return self.disk_cache.contains(fid).ok_or(libc::EIO);
+        }
+        let item = self.remote_item(fid)?;
+        self.disk_cache.validate(&item);
+        if let Some(path) = self.disk_cache.contains(fid) {
+            return Ok(path);
+        }
+        self.disk_cache.fetch(&self.client, &item).map_err(|e| {
+            warn!("Failed to download {}: {}", fid, e);
+            e.errno()
+        })?;
+        self.disk_cache.contains(fid).ok_or_else(|| {
+            warn!("Failed to find {} in cache after downloaded", fid);
+            libc::EIO
+        })
+    }
+
+    // evict downloaded files over the size limit, but open and pinned ones
+    fn evict_cache(&self) {
+        self.disk_cache.evict(|fid| {
+            let map = self.fid_map.read().unwrap();
+            map.has_handles(&fid)
+                || map
+                    .ancestors(&fid)
+                    .iter()
+                    .any(|f| self.disk_cache.is_pinned(*f))
+        });
+    }
+
+    // the extended attributes which item has
+    // files which are not uploaded yet have nothing from rec
+    fn xattr_names(&self, item: &RecListItem) -> Vec<&'static str> {
+        let remote = !item.fid.is_created();
+        XATTRS
+            .iter()
+            .copied()
+            .filter(|name| match *name {
+                XATTR_FID | XATTR_DISK => remote,
+                XATTR_HASH => remote && item.hash.is_some(),
+                XATTR_DOWNLOAD_URL => {
+                    remote && item.ftype == FileType::RegularFile && !self.is_offline()
+                }
+                XATTR_UPLOADS => item.fid == Fid::root(),
+                _ => true,
+            })
+            .collect()
+    }
+
+    // whether fid or a folder above it is pinned
+    fn is_pinned(&self, fid: Fid) -> bool {
+        let ancestors = self.fid_map.read().unwrap().ancestors(&fid);
+        ancestors.iter().any(|f| self.disk_cache.is_pinned(*f))
+    }
+
+    // whether the content of fid is completely local: for a folder, everything listed in it
+    fn is_cached(&self, fid: Fid) -> bool {
+        let mut files = Vec::new();
+        {
+            let map = self.fid_map.read().unwrap();
+            let mut dirs = Vec::new();
+            match map.get_item(&fid) {
+                Some(item) if item.ftype == FileType::Directory => dirs.push(fid),
+                Some(_) => files.push(fid),
+                None => return false,
+            }
+            while let Some(dir) = dirs.pop() {
+                let children = match map.get_listing(&dir).and_then(|l| l.children.as_ref()) {
+                    Some(children) => children,
+                    None => return false,
+                };
+                for child in children.iter() {
+                    if child.ftype == FileType::Directory {
+                        dirs.push(child.fid);
+                    } else {
+                        files.push(child.fid);
+                    }
+                }
+            }
+        }
+        files
+            .into_iter()
+            .all(|f| f.is_created() || self.disk_cache.contains(f).is_some())
+    }
+
+    // download pinned files and folders in background
+    fn sync_pinned(&self, fids: Vec<Fid>) {
+        let client = self.client.clone();
+        let fid_map = self.fid_map.clone();
+        let cache = self.disk_cache.clone();
+        std::thread::spawn(move || {
+            for fid in fids {
+                poll::sync_pinned(&client, &fid_map, &cache, fid);
+            }
+        });
+    }
+
+    // a remote file, as listed in its parent
+    fn remote_item(&self, fid: Fid) -> Result<RecListItem, libc::c_int> {
+        let parent = self
+            .fid_map
+            .read()
+            .unwrap()
+            .get_parent_fid(&fid)
+            .ok_or(libc::ENOENT)?;
+        self.get_item(fid, parent)
+    }
+
+    // upload the modified local copy of a remote file, and swap it in for the old one
+    // rec has no API to overwrite a file, so the new content is uploaded under a temporary
+    // name first, then the old file is recycled and the new one takes over its name
+    fn write_back(&self, fid: Fid) -> Result<Fid, libc::c_int> {
+        let parent = {
+            let map = self.fid_map.read().unwrap();
+            map.get_parent_fid(&fid).flatten().ok_or(libc::EIO)?
+        };
+        let item = self.get_item(fid, Some(parent))?;
+        let path = self.disk_cache.contains(fid).ok_or(libc::EIO)?;
+        if std::fs::metadata(&path).map_err(|_| libc::EIO)?.len() == 0 {
+            // rec rejects empty uploads: keep the old content rather than recycling it
+            warn!(
+                "Write back of {} failed: rec cannot store an empty file, the old content is kept",
+                item.name
+            );
+            self.disk_cache.discard_dirty(fid);
+            return Err(libc::EINVAL);
+        }
+        let tmp_name = format!(
+            ".{}.recfs-{}",
+            item.name,
+            thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(8)
+                .map(char::from)
+                .collect::<String>()
+        );
+        info!("Write back {} ({}) via {}", item.name, fid, tmp_name);
+        let new_fid = self
+            .client
+            .upload(parent, Path::new(&path), tmp_name.clone())
+            .map_err(|e| {
+                // most programs ignore the return value of close()
+                // so here warn! to notify users of uploading failure
+                warn!("Write back of {} failed when uploading: {}", item.name, e);
+                e.errno()
+            })?;
+        let listing = self.req_update_listing(parent)?;
+        let new_item = listing
+            .children
+            .ok_or(libc::ENOTDIR)?
+            .into_iter()
+            .find(|i| i.fid == new_fid)
+            .ok_or(libc::EIO)?;
+        if let Err(e) = cache::check_hash(Path::new(&path), new_item.hash.as_deref()) {
+            error!("Write back of {} is corrupted on rec: {}", item.name, e);
+            let _ =
+                self.client
+                    .operation(Operation::Delete, new_item.fid, FileType::RegularFile, None);
+            self.req_update_listing(parent)?;
+            return Err(e.errno());
+        }
+
+        if let Err(e) = self
+            .client
+            .operation(Operation::Delete, fid, FileType::RegularFile, None)
+        {
+            warn!(
+                "Write back of {} failed when recycling the old file: {}",
+                item.name, e
+            );
+            let _ =
+                self.client
+                    .operation(Operation::Delete, new_item.fid, FileType::RegularFile, None);
+            self.req_update_listing(parent)?;
+            return Err(e.errno());
+        }
+        if let Err(e) = self.client.rename_ext(new_item.fid, item.name.clone()) {
+            warn!(
+                "Write back of {} failed when renaming, new content is kept as {}: {}",
+                item.name, tmp_name, e
+            );
+            self.req_update_listing(parent)?;
+            return Err(e.errno());
+        }
+        self.req_update_listing(parent)?;
+
+        if let Err(e) = self.disk_cache.replace(fid, &new_item) {
+            warn!("Failed to keep local copy of {}: {}", item.name, e);
+        }
+        self.fid_map
+            .write()
+            .unwrap()
+            .replace_fid(&fid, &new_item.fid);
+        Ok(new_item.fid)
+    }
+
     fn create_in_cache(
         &self,
         parent: &Path,
         name: &std::ffi::OsStr,
     ) -> Result<(Fid, Fid), libc::c_int> {
         let (parent_fid, _) = self.req_fid(parent)?;
+        let name = name.to_str().ok_or(libc::EINVAL)?.to_string();
         let fid = self
             .disk_cache
-            .create(parent_fid, name.to_str().ok_or(libc::EINVAL)?.to_string())
+            .create(parent_fid, name.clone())
             .map_err(|_| libc::EIO)?;
+        self.fid_map.write().unwrap().add_pending(
+            &parent_fid,
+            RecListItem {
+                bytes: 0,
+                name,
+                hash: None,
+                fid,
+                ftype: FileType::RegularFile,
+                time_updated: SystemTime::now(),
+            },
+        );
 
         Ok((fid, parent_fid))
     }
@@ -537,10 +1351,24 @@ impl RecFs {
     fn delete(&self, parent: &Path, name: &std::ffi::OsStr) -> fuse_mt::ResultEmpty {
         let path = parent.join(name);
         let (fid, parent) = self.req_fid(&path)?;
+        if fid.is_created() {
+            // not on rec yet: forget it, so that it is never uploaded
+            // an open one is forgotten when its last handle is released
+            self.uploads.forget(fid);
+            let mut map = self.fid_map.write().unwrap();
+            map.remove_pending(&fid);
+            if map.has_handles(&fid) {
+                self.disk_cache.unjournal(fid);
+            } else {
+                drop(map);
+                self.disk_cache.forget_created(fid);
+            }
+            return Ok(());
+        }
         let item = self.get_item(fid, parent)?;
         self.client
             .operation(Operation::Delete, fid, item.ftype, None)
-            .map_err(|_| libc::EIO)?;
+            .map_err(rec_errno)?;
         self.req_update_listing(parent.unwrap_or_else(Fid::root))?;
         Ok(())
     }
@@ -549,88 +1377,124 @@ impl RecFs {
         let mut parent = None;
         let mut fid = Fid::root();
         let mut is_dir = true;
+        let offline = self.is_offline();
 
         for c in path.components().skip(1) {
+            let s = c.as_os_str().to_string_lossy();
             // is current fid in cache?
             {
                 let map = self.fid_map.read().unwrap();
                 if let Some(n) = map.borrow().get_listing(&fid) {
-                    let mut found = false;
-                    if let Some(children) = &n.children {
-                        for child in children.iter() {
-                            if child.name == c.as_os_str().to_string_lossy() {
-                                debug!("found in cache: {:?}", child);
-                                parent = Some(fid);
-                                fid = child.fid;
-                                is_dir = child.ftype == FileType::Directory;
-                                found = true;
-                                break;
+                    let fresh = map.is_fresh(&fid);
+                    let usable = map.is_usable(&fid) || offline;
+                    let child = n
+                        .children
+                        .as_ref()
+                        .and_then(|children| children.iter().find(|i| i.name == s));
+                    match child {
+                        Some(child) if usable => {
+                            debug!("found in cache: {:?}", child);
+                            if !fresh {
+                                self.refresh(fid);
                             }
+                            parent = Some(fid);
+                            fid = child.fid;
+                            is_dir = child.ftype == FileType::Directory;
+                            continue;
                         }
-                    }
-
-                    if found {
-                        continue;
-                    }
-
-                    if self.fast_path {
-                        // file does not exist in cache: stop in fast path
-                        return Err(libc::ENOENT);
+                        // file does not exist in cache, and did not exist on rec a moment ago
+                        None if fresh && map.is_missing(&fid, &s) => {
+                            return Err(libc::ENOENT);
+                        }
+                        // the last known listing is all we have
+                        None if offline && n.children.is_some() => {
+                            return Err(libc::ENOENT);
+                        }
+                        _ => {}
                     }
                 }
             }
+            if offline {
+                // a folder which was never listed
+                return Err(libc::EIO);
+            }
 
-            // not found in cache, request from server now
+            // not found in cache or expired, request from server now
             info!("not found in cache: {:?}", c);
-            let items = self.client.list(fid).map_err(|_| libc::ENOENT)?;
+            let items = match self.client.list(fid) {
+                Ok(items) => items,
+                // look it up again in offline mode
+                Err(e) if self.check_unreachable(&e) => return self.req_fid(path),
+                Err(e) => return Err(rec_errno(e)),
+            };
             // Update listing
-            {
+            let items = {
                 let mut map = self.fid_map.write().unwrap();
-                let node = map.borrow_mut().get_listing_mut(fid);
-                node.children = Some(items.clone());
+                let items = map.borrow_mut().set_children(fid, items);
                 for child in items.iter() {
                     map.borrow_mut()
                         .get_parentmap_mut()
                         .insert(child.fid, Some(fid));
                 }
-            }
-            let s = c.as_os_str().to_string_lossy();
+                items
+            };
             match items.iter().find(|i| i.name == s) {
                 Some(item) => {
                     parent = Some(fid);
                     fid = item.fid;
                     is_dir = item.ftype == FileType::Directory;
                 }
-                None => return Err(libc::ENOENT),
+                None => {
+                    self.fid_map.write().unwrap().set_missing(&fid, &s);
+                    return Err(libc::ENOENT);
+                }
             }
         }
-        // if current fid is dir and not in cache (including /), request from server
-        let is_in_fidmap = self
-            .fid_map
-            .read()
-            .unwrap()
-            .borrow()
-            .get_listing(&fid)
-            .is_some();
+        // if current fid is dir and not in cache (including /) or expired, request from server
+        let (is_in_fidmap, is_fresh, is_restored) = {
+            let map = self.fid_map.read().unwrap();
+            (
+                map.get_listing(&fid).is_some(),
+                map.is_fresh(&fid),
+                map.is_restored(&fid),
+            )
+        };
         debug!(
-            "fid: {}, is_dir: {}, is_in_fidmap: {}",
-            fid, is_dir, is_in_fidmap
+            "fid: {}, is_dir: {}, is_in_fidmap: {}, is_fresh: {}",
+            fid, is_dir, is_in_fidmap, is_fresh
         );
-        if !is_in_fidmap {
+        if offline || (is_dir && is_restored) {
             if is_dir {
-                let items = self.client.list(fid).map_err(|_| libc::ENOENT)?;
+                self.refresh(fid);
+            }
+            if !is_in_fidmap {
+                // a folder which is not listed yet fails in readdir()
+                self.fid_map.write().unwrap().borrow_mut().update_fid(
+                    &fid,
+                    parent.as_ref(),
+                    &FidCachedList::default(),
+                );
+            }
+        } else if !is_in_fidmap || (is_dir && !is_fresh) {
+            if is_dir {
+                let items = match self.client.list(fid) {
+                    Ok(items) => items,
+                    Err(e) if self.check_unreachable(&e) => return self.req_fid(path),
+                    Err(e) => return Err(rec_errno(e)),
+                };
                 self.fid_map.write().unwrap().borrow_mut().update_fid(
                     &fid,
                     parent.as_ref(),
                     &FidCachedList {
                         children: Some(items),
+                        ..Default::default()
                     },
                 );
             } else {
                 self.fid_map.write().unwrap().borrow_mut().update_fid(
                     &fid,
                     parent.as_ref(),
-                    &FidCachedList { children: None },
+                    &FidCachedList::default(),
                 );
             }
         }
@@ -649,7 +1513,7 @@ impl RecFs {
     fn get_fid_with_parent(&self, fh: u64) -> Result<(Fid, Option<Fid>), libc::c_int> {
         let map = self.fid_map.read().unwrap();
         let fid = map.borrow().get_fid_by_fh(fh).ok_or(libc::EBADF)?;
-        let parent = map.borrow().get_parent_fid(&fid).unwrap();
+        let parent = map.borrow().get_parent_fid(&fid).ok_or(libc::ENOENT)?;
         Ok((fid, parent))
     }
 
@@ -659,6 +1523,9 @@ impl RecFs {
             None => return Ok(RecListItem::root()),
         };
         let map = self.fid_map.read().unwrap();
+        if let Some(item) = map.get_unlinked(&fid) {
+            return Ok(item);
+        }
         let listing = map.get_listing(&parent).ok_or(libc::ENOENT)?;
         listing
             .children
@@ -682,13 +1549,13 @@ impl RecFs {
     #[allow(dead_code)]
     fn req_item(&self, fid: Fid, parent_fid: Option<Fid>) -> Result<RecListItem, libc::c_int> {
         if let Some(parent_fid) = parent_fid {
-            let items = self.client.list(parent_fid).map_err(|_| libc::ENOENT)?;
+            let items = self.client.list(parent_fid).map_err(rec_errno)?;
             // update listing
-            self.fid_map
+            let items = self
+                .fid_map
                 .write()
                 .unwrap()
-                .get_listing_mut(parent_fid)
-                .children = Some(items.clone());
+                .set_children(parent_fid, items);
             Ok(items.into_iter().find(|i| i.fid == fid).unwrap())
         } else {
             Ok(RecListItem::root())
@@ -696,10 +1563,12 @@ impl RecFs {
     }
 
     fn req_update_listing(&self, fid: Fid) -> Result<FidCachedList, libc::c_int> {
-        let items = self.client.list(fid).map_err(|_| libc::ENOENT)?;
-        self.fid_map.write().unwrap().get_listing_mut(fid).children = Some(items.clone());
+        let items = self.client.list(fid).map_err(rec_errno)?;
+        let items = self.fid_map.write().unwrap().set_children(fid, items);
         Ok(FidCachedList {
             children: Some(items),
+            listed: Some(Instant::now()),
+            ..Default::default()
         })
     }
 }
@@ -10,6 +10,13 @@ mod client;
 mod fid;
 mod fidmap;
 mod fs;
+#[cfg(test)]
+mod mockd;
+mod poll;
+mod prefetch;
+#[cfg(test)]
+mod tests;
+mod upload;
 
 #[derive(Parser)]
 pub struct Args {
@@ -20,9 +27,81 @@ pub struct Args {
     /// The mountpoint
     mountpoint: PathBuf,
 
-    #[arg(long, default_value_t = false)]
-    /// Request server for non-existing files in local tree structure cache
-    no_fast_path: bool,
+    #[arg(long, env = "RECFS_API_URL", default_value = client::APIURL)]
+    /// Base URL of the rec API
+    api_url: String,
+
+    #[arg(long, env = "RECFS_CLIENT_ID", default_value = client::CLIENTID)]
+    /// Client id used for login and token refreshing
+    client_id: String,
+
+    #[arg(long, env = "RECFS_SIGNATURE", default_value = client::SIGNATURE)]
+    /// Signature prefix used for login
+    signature: String,
+
+    #[arg(long, env = "RECFS_AES_KEY", default_value = client::AESKEY)]
+    /// Base64-encoded AES key for encrypted login messages
+    aes_key: String,
+
+    #[arg(long, default_value_t = 4)]
+    /// Number of tries for requests failed with transient errors
+    retries: u32,
+
+    #[arg(long, default_value_t = 500)]
+    /// Initial delay before retrying a request, in milliseconds (doubled on each retry)
+    retry_delay: u64,
+
+    #[arg(long, default_value_t = 1024)]
+    /// Size limit of downloaded files kept in the cache, in MiB
+    cache_size: u64,
+
+    #[arg(long)]
+    /// Keep downloaded files in this folder and reuse them across mounts
+    cache_dir: Option<PathBuf>,
+
+    #[arg(long, default_value_t = 60)]
+    /// Seconds after which a cached folder listing is fetched again from rec
+    listing_ttl: u64,
+
+    #[arg(long, default_value_t = 5)]
+    /// Seconds during which a file not found on rec is not looked up again
+    negative_ttl: u64,
+
+    #[arg(long)]
+    /// Look for changes made on rec by other clients every this many seconds
+    poll_interval: Option<u64>,
+
+    #[arg(long, default_value_t = 4)]
+    /// Number of threads uploading new files in background
+    upload_workers: usize,
+
+    #[arg(long, default_value_t = 4)]
+    /// Number of chunks of a file uploaded at the same time
+    upload_parallelism: usize,
+
+    #[arg(long, default_value_t = 8)]
+    /// Number of files downloaded ahead when the files of a folder are read in order (0 to disable)
+    prefetch_files: usize,
+
+    #[arg(long, default_value_t = 256)]
+    /// Size limit of the files downloaded ahead in a folder, in MiB
+    prefetch_size: u64,
+
+    #[arg(long, default_value_t = 600)]
+    /// Seconds during which a download url given by rec is reused
+    download_url_ttl: u64,
+
+    #[arg(long, default_value_t = 4)]
+    /// Number of connections a large file is downloaded over
+    download_connections: usize,
+
+    #[arg(long, default_value_t = 8)]
+    /// Size below which a download is not split over more connections, in MiB
+    min_split_size: u64,
+
+    #[arg(long, default_value_t = false, requires = "cache_dir")]
+    /// Do not connect to rec, serve folders and files kept in --cache-dir read-only
+    offline: bool,
 }
 
 fn main() {
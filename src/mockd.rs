@@ -0,0 +1,959 @@
+// A local stand-in for the subset of the rec v2 API used by `RecClient`.
+// File contents are stored under `<root>/objects`, the tree itself in `<root>/index.json`.
+// This file is shared by the `recfs-mockd` binary and the test suite, so it must not
+// depend on anything else in the crate.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use binary_macros::base64;
+use libaes::Cipher;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use time::macros::offset;
+use time::OffsetDateTime;
+use tiny_http::{Header, Method, Request, Response, Server};
+use url::Url;
+use uuid::Uuid;
+
+static AESKEY: &[u8; 16] = base64!("Z1pNbFZmMmVqd2wwVmlHNA==");
+
+const ROOT: &str = "0";
+const WORKERS: usize = 8;
+const DEFAULT_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+const DEFAULT_TOTAL_SPACE: u64 = 1 << 40;
+
+// rec status codes returned by the mock
+const OK: i32 = 200;
+const BAD_REQUEST: i32 = 400;
+const UNAUTHORIZED: i32 = 401;
+const NOT_FOUND: i32 = 404;
+const CONFLICT: i32 = 409;
+const INSUFFICIENT_SPACE: i32 = 507;
+
+type ApiResult = Result<Value, (i32, String)>;
+type HttpResponse = Response<Cursor<Vec<u8>>>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Node {
+    name: String,
+    ext: String,
+    parent: String,
+    folder: bool,
+    disk: String, // cloud, backup or recycle
+    bytes: u64,
+    hash: String,
+    updated: String,
+}
+
+impl Node {
+    fn full(name: &str, ext: &str) -> String {
+        if ext.is_empty() {
+            name.to_owned()
+        } else {
+            format!("{}.{}", name, ext)
+        }
+    }
+
+    fn full_name(&self) -> String {
+        Node::full(&self.name, &self.ext)
+    }
+}
+
+struct Upload {
+    parent: String,
+    name: String,
+    bytes: u64,
+    chunks: u64,
+}
+
+struct State {
+    root: PathBuf,
+    base_url: String,
+    nodes: HashMap<String, Node>,
+    uploads: HashMap<String, Upload>,
+    access_token: String,
+    refresh_token: String,
+    chunk_size: u64,
+    total_space: u64,
+    // the next requests to be failed with these HTTP status codes
+    failures: Vec<u16>,
+    // the next folder listings to be failed with HTTP 503
+    list_failures: usize,
+    // bytes of file content served so far
+    downloaded: u64,
+    // downloads asking for a range of a file so far
+    ranges: u64,
+    // download requests to the api so far
+    url_requests: u64,
+    // download urls given before the last change of this are refused with 403
+    url_key: u64,
+    // folder listings served so far
+    listed: u64,
+    // upload chunks wait while this is set
+    hold_uploads: bool,
+    // upload chunks waiting for that
+    held: usize,
+    // upload chunks stored so far
+    parts: u64,
+    // the number of upload chunks accepted before the others fail
+    parts_left: Option<u64>,
+    // flip the first byte of uploaded files, as a broken storage backend would
+    corrupt_uploads: bool,
+    // the next downloads send at most this many bytes each, as a flaky connection would
+    cut_downloads: Vec<u64>,
+    // send whole files with 200 to Range requests, as some servers do
+    ignore_ranges: bool,
+}
+
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    stop: Arc<AtomicBool>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl MockServer {
+    pub fn start(listen: &str, root: &Path) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(root.join("objects"))?;
+        std::fs::create_dir_all(root.join("uploads"))?;
+        let nodes = match std::fs::read(root.join("index.json")) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(_) => HashMap::new(),
+        };
+
+        let server = Arc::new(Server::http(listen).map_err(|e| anyhow::anyhow!(e))?);
+        let addr = server
+            .server_addr()
+            .to_ip()
+            .ok_or_else(|| anyhow::anyhow!("mockd must listen on an IP address"))?;
+        info!("mockd: serving {} on http://{}", root.display(), addr);
+
+        let state = Arc::new(Mutex::new(State {
+            root: root.to_path_buf(),
+            base_url: format!("http://{}", addr),
+            nodes,
+            uploads: HashMap::new(),
+            access_token: new_id().simple().to_string(),
+            refresh_token: new_id().simple().to_string(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            total_space: DEFAULT_TOTAL_SPACE,
+            failures: Vec::new(),
+            list_failures: 0,
+            downloaded: 0,
+            ranges: 0,
+            url_requests: 0,
+            url_key: 0,
+            listed: 0,
+            hold_uploads: false,
+            held: 0,
+            parts: 0,
+            parts_left: None,
+            corrupt_uploads: false,
+            cut_downloads: Vec::new(),
+            ignore_ranges: false,
+        }));
+        let stop = Arc::new(AtomicBool::new(false));
+        let workers = (0..WORKERS)
+            .map(|_| {
+                let server = server.clone();
+                let state = state.clone();
+                let stop = stop.clone();
+                std::thread::spawn(move || {
+                    while !stop.load(Ordering::SeqCst) {
+                        match server.recv_timeout(Duration::from_millis(100)) {
+                            Ok(Some(req)) => handle(&state, req),
+                            Ok(None) => {}
+                            Err(e) => warn!("mockd: recv failed: {}", e),
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        Ok(Self {
+            addr,
+            state,
+            stop,
+            workers,
+        })
+    }
+
+    pub fn api_url(&self) -> String {
+        format!("http://{}/api/v2/", self.addr)
+    }
+
+    // (access token, refresh token) currently accepted by the server
+    pub fn tokens(&self) -> (String, String) {
+        let state = self.state.lock().unwrap();
+        (state.access_token.clone(), state.refresh_token.clone())
+    }
+
+    pub fn wait(mut self) {
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+
+    pub fn set_chunk_size(&self, size: u64) {
+        self.state.lock().unwrap().chunk_size = size;
+    }
+
+    pub fn set_total_space(&self, size: u64) {
+        self.state.lock().unwrap().total_space = size;
+    }
+
+    // fail the next `count` requests with HTTP `status`, without processing them
+    pub fn fail_next(&self, count: usize, status: u16) {
+        let mut state = self.state.lock().unwrap();
+        state.failures.extend(std::iter::repeat_n(status, count));
+    }
+
+    // fail the next `count` folder listings with HTTP 503, leaving other requests alone
+    pub fn fail_lists(&self, count: usize) {
+        self.state.lock().unwrap().list_failures = count;
+    }
+
+    // make uploads of file content wait until this is called with false
+    pub fn hold_uploads(&self, hold: bool) {
+        self.state.lock().unwrap().hold_uploads = hold;
+    }
+
+    // accept `count` more upload chunks, then fail the others with HTTP 500 until called with None
+    pub fn fail_parts_after(&self, count: Option<u64>) {
+        self.state.lock().unwrap().parts_left = count;
+    }
+
+    // upload chunks waiting for hold_uploads(false)
+    pub fn held(&self) -> usize {
+        self.state.lock().unwrap().held
+    }
+
+    pub fn parts(&self) -> u64 {
+        self.state.lock().unwrap().parts
+    }
+
+    // uploads begun but not completed
+    pub fn uploading(&self) -> usize {
+        self.state.lock().unwrap().uploads.len()
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.state.lock().unwrap().downloaded
+    }
+
+    pub fn ranges(&self) -> u64 {
+        self.state.lock().unwrap().ranges
+    }
+
+    pub fn url_requests(&self) -> u64 {
+        self.state.lock().unwrap().url_requests
+    }
+
+    // make the download urls given so far expire
+    pub fn expire_urls(&self) {
+        self.state.lock().unwrap().url_key += 1;
+    }
+
+    pub fn listed(&self) -> u64 {
+        self.state.lock().unwrap().listed
+    }
+
+    pub fn add_folder(&self, parent: &str, name: &str) -> String {
+        let mut state = self.state.lock().unwrap();
+        state.insert_node(parent, name, true, None)
+    }
+
+    pub fn add_file(&self, parent: &str, name: &str, content: &[u8]) -> String {
+        let mut state = self.state.lock().unwrap();
+        state.insert_node(parent, name, false, Some(content))
+    }
+
+    // change the content of a file in place, keeping its id
+    pub fn set_content(&self, id: &str, content: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        std::fs::write(state.object_path(id), content).unwrap();
+        let node = state.nodes.get_mut(id).unwrap();
+        node.bytes = content.len() as u64;
+        node.hash = format!("{:x}", md5::compute(content));
+        node.updated = now();
+        state.save();
+    }
+
+    // make the listing give a wrong hash for a file, without changing its content
+    pub fn set_hash(&self, id: &str, hash: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.nodes.get_mut(id).unwrap().hash = hash.to_owned();
+        state.save();
+    }
+
+    pub fn ignore_ranges(&self, ignore: bool) {
+        self.state.lock().unwrap().ignore_ranges = ignore;
+    }
+
+    // end the next `count` downloads after `bytes` bytes
+    pub fn cut_downloads(&self, count: usize, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .cut_downloads
+            .extend(std::iter::repeat_n(bytes, count));
+    }
+
+    pub fn corrupt_uploads(&self, corrupt: bool) {
+        self.state.lock().unwrap().corrupt_uploads = corrupt;
+    }
+
+    // move a file or folder to the recycle bin, as the web interface does
+    pub fn recycle(&self, id: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.nodes.get_mut(id).unwrap().disk = "recycle".to_owned();
+        state.save();
+    }
+
+    // find a child (not recycled) by its full name
+    pub fn find(&self, parent: &str, name: &str) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        state.child_named(parent, "cloud", name).cloned()
+    }
+
+    pub fn disk(&self, id: &str) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        state.nodes.get(id).map(|n| n.disk.clone())
+    }
+
+    pub fn content(&self, id: &str) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        std::fs::read(state.object_path(id)).ok()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn handle(state: &Mutex<State>, mut req: Request) {
+    let url = match Url::parse(&format!("http://mockd{}", req.url())) {
+        Ok(url) => url,
+        Err(e) => {
+            warn!("mockd: bad url {}: {}", req.url(), e);
+            let _ = req.respond(Response::from_string("").with_status_code(400));
+            return;
+        }
+    };
+    let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+    let header = |name: &'static str| {
+        req.headers()
+            .iter()
+            .find(|h| h.field.equiv(name))
+            .map(|h| h.value.to_string())
+    };
+    let token = header("x-auth-token");
+    let range = header("Range");
+    let mut body = Vec::new();
+    if let Err(e) = req.as_reader().read_to_end(&mut body) {
+        warn!("mockd: failed to read body: {}", e);
+    }
+    debug!("mockd: {} {}", req.method(), req.url());
+    if url.path().starts_with("/mockd/upload/") && state.lock().unwrap().hold_uploads {
+        state.lock().unwrap().held += 1;
+        while state.lock().unwrap().hold_uploads {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        state.lock().unwrap().held -= 1;
+    }
+
+    let resp = state.lock().unwrap().route(
+        req.method(),
+        url.path(),
+        &query,
+        token.as_deref(),
+        range.as_deref(),
+        body,
+    );
+    if let Err(e) = req.respond(resp) {
+        warn!("mockd: failed to respond: {}", e);
+    }
+}
+
+fn rec_response(res: ApiResult) -> HttpResponse {
+    let body = match res {
+        Ok(entity) => json!({ "entity": entity, "message": "success", "status_code": OK }),
+        Err((status_code, message)) => {
+            json!({ "entity": null, "message": message, "status_code": status_code })
+        }
+    };
+    Response::from_string(body.to_string())
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap())
+}
+
+fn not_found(what: &str) -> (i32, String) {
+    (NOT_FOUND, format!("{} not found", what))
+}
+
+fn split_name(full: &str) -> (String, String) {
+    match full.rsplit_once('.') {
+        Some((name, ext)) if !name.is_empty() => (name.to_owned(), ext.to_owned()),
+        _ => (full.to_owned(), String::new()),
+    }
+}
+
+fn new_id() -> Uuid {
+    uuid::Builder::from_random_bytes(rand::random()).into_uuid()
+}
+
+fn now() -> String {
+    let t = OffsetDateTime::now_utc().to_offset(offset!(+8:00));
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        t.year(),
+        t.month() as u8,
+        t.day(),
+        t.hour(),
+        t.minute(),
+        t.second()
+    )
+}
+
+fn aes_encrypt(data: &[u8]) -> String {
+    let cipher = Cipher::new_128(AESKEY);
+    let mut iv = *AESKEY;
+    iv.reverse();
+    base64::encode(cipher.cbc_encrypt(&iv, data))
+}
+
+impl State {
+    fn route(
+        &mut self,
+        method: &Method,
+        path: &str,
+        query: &HashMap<String, String>,
+        token: Option<&str>,
+        range: Option<&str>,
+        body: Vec<u8>,
+    ) -> HttpResponse {
+        if !self.failures.is_empty() {
+            let status = self.failures.remove(0);
+            return Response::from_string("").with_status_code(status);
+        }
+        if path.starts_with("/api/v2/folder/content/") && self.list_failures > 0 {
+            self.list_failures -= 1;
+            return Response::from_string("").with_status_code(503);
+        }
+        if let Some(api) = path.strip_prefix("/api/v2/") {
+            let json: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+            return rec_response(self.api(method, api, query, token, &json));
+        }
+        if let (Method::Get, Some(id)) = (method, path.strip_prefix("/mockd/download/")) {
+            if query.get("key") != Some(&self.url_key.to_string()) {
+                return Response::from_string("").with_status_code(403);
+            }
+            return match std::fs::read(self.object_path(id)) {
+                Ok(data) => self.serve(data, range),
+                Err(_) => Response::from_string("").with_status_code(404),
+            };
+        }
+        if let (Method::Put, Some(part)) = (method, path.strip_prefix("/mockd/upload/")) {
+            if token != Some(self.access_token.as_str()) {
+                return Response::from_string("").with_status_code(401);
+            }
+            match self.parts_left {
+                Some(0) => return Response::from_string("").with_status_code(500),
+                Some(n) => self.parts_left = Some(n - 1),
+                None => {}
+            }
+            return match self.upload_part(part, &body) {
+                Ok(()) => Response::from_string(""),
+                Err(e) => Response::from_string(e).with_status_code(400),
+            };
+        }
+        Response::from_string("").with_status_code(404)
+    }
+
+    // file content, or the part of it asked for by a "Range: bytes=start-[end]" header
+    fn serve(&mut self, data: Vec<u8>, range: Option<&str>) -> HttpResponse {
+        let range = match range {
+            Some(range) if !self.ignore_ranges => range,
+            _ => {
+                let mut data = data;
+                if !self.cut_downloads.is_empty() {
+                    let cut = self.cut_downloads.remove(0);
+                    data.truncate(cut as usize);
+                }
+                self.downloaded += data.len() as u64;
+                return Response::from_data(data);
+            }
+        };
+        self.ranges += 1;
+        let total = data.len() as u64;
+        let bounds = range.strip_prefix("bytes=").and_then(|r| r.split_once('-'));
+        let (start, end) = match bounds {
+            Some((start, end)) => match (start.parse::<u64>(), end) {
+                (Ok(start), "") => (start, total.saturating_sub(1)),
+                (Ok(start), end) => match end.parse::<u64>() {
+                    Ok(end) => (start, end.min(total.saturating_sub(1))),
+                    Err(_) => return Response::from_string("").with_status_code(400),
+                },
+                _ => return Response::from_string("").with_status_code(400),
+            },
+            None => return Response::from_string("").with_status_code(400),
+        };
+        if start >= total || start > end {
+            return Response::from_string("").with_status_code(416).with_header(
+                Header::from_bytes(&b"Content-Range"[..], format!("bytes */{}", total)).unwrap(),
+            );
+        }
+        let mut part = data[start as usize..=end as usize].to_vec();
+        if !self.cut_downloads.is_empty() {
+            let cut = self.cut_downloads.remove(0);
+            part.truncate(cut as usize);
+        }
+        self.downloaded += part.len() as u64;
+        Response::from_data(part).with_status_code(206).with_header(
+            Header::from_bytes(
+                &b"Content-Range"[..],
+                format!("bytes {}-{}/{}", start, end, total),
+            )
+            .unwrap(),
+        )
+    }
+
+    fn api(
+        &mut self,
+        method: &Method,
+        api: &str,
+        query: &HashMap<String, String>,
+        token: Option<&str>,
+        json: &Value,
+    ) -> ApiResult {
+        // endpoints that do not need a token
+        match (method, api) {
+            (Method::Get, "client/tempticket") => return Ok(json!({ "tempticket": "mockd" })),
+            (Method::Post, "user/login") => return Ok(self.login()),
+            (Method::Post, "user/refresh/token") => return self.refresh(json),
+            _ => {}
+        }
+        if token != Some(self.access_token.as_str()) {
+            return Err((UNAUTHORIZED, "token expired".to_owned()));
+        }
+        let res = match (method, api) {
+            (Method::Get, "userinfo") => Ok(self.userinfo()),
+            (Method::Get, p) if p.starts_with("folder/content/") => {
+                self.listed += 1;
+                self.list(&p["folder/content/".len()..], query)
+            }
+            (Method::Get, p) if p.starts_with("file/") => {
+                self.upload_begin(&p["file/".len()..], query)
+            }
+            (Method::Post, "file/complete") => self.upload_complete(json),
+            (Method::Post, "download") => self.download(json),
+            (Method::Post, "operationFileOrFolder") => self.operation(json),
+            (Method::Post, "rename") => self.rename(json, false),
+            (Method::Post, "rename_ext") => self.rename(json, true),
+            (Method::Post, "folder/tree") => self.mkdir(json),
+            _ => Err(not_found(api)),
+        };
+        if res.is_ok() && *method == Method::Post {
+            self.save();
+        }
+        res
+    }
+
+    fn save(&self) {
+        let data = serde_json::to_vec(&self.nodes).unwrap();
+        if let Err(e) = std::fs::write(self.root.join("index.json"), data) {
+            warn!("mockd: failed to save index: {}", e);
+        }
+    }
+
+    fn object_path(&self, id: &str) -> PathBuf {
+        self.root.join("objects").join(id)
+    }
+
+    fn is_folder(&self, id: &str) -> bool {
+        id == ROOT || self.nodes.get(id).map(|n| n.folder).unwrap_or(false)
+    }
+
+    fn child_named(&self, parent: &str, disk: &str, full_name: &str) -> Option<&String> {
+        self.nodes
+            .iter()
+            .find(|(_, n)| n.parent == parent && n.disk == disk && n.full_name() == full_name)
+            .map(|(id, _)| id)
+    }
+
+    // rec appends "(n)" instead of overwriting existing files
+    fn unique_name(&self, parent: &str, full_name: &str) -> (String, String) {
+        let (name, ext) = split_name(full_name);
+        let mut candidate = (name.clone(), ext.clone());
+        let mut n = 1;
+        while self
+            .child_named(parent, "cloud", &Node::full(&candidate.0, &candidate.1))
+            .is_some()
+        {
+            candidate = (format!("{}({})", name, n), ext.clone());
+            n += 1;
+        }
+        candidate
+    }
+
+    fn insert_node(
+        &mut self,
+        parent: &str,
+        full_name: &str,
+        folder: bool,
+        content: Option<&[u8]>,
+    ) -> String {
+        let id = new_id().to_string();
+        let (name, ext) = if folder {
+            (full_name.to_owned(), String::new())
+        } else {
+            self.unique_name(parent, full_name)
+        };
+        let content = content.unwrap_or_default();
+        if !folder {
+            std::fs::write(self.object_path(&id), content).unwrap();
+        }
+        self.nodes.insert(
+            id.clone(),
+            Node {
+                name,
+                ext,
+                parent: parent.to_owned(),
+                folder,
+                disk: "cloud".to_owned(),
+                bytes: content.len() as u64,
+                hash: if folder {
+                    String::new()
+                } else {
+                    format!("{:x}", md5::compute(content))
+                },
+                updated: now(),
+            },
+        );
+        self.save();
+        id
+    }
+
+    fn login(&mut self) -> Value {
+        self.access_token = new_id().simple().to_string();
+        self.refresh_token = new_id().simple().to_string();
+        let userauth = json!({
+            "gid": "1",
+            "username": "mockd",
+            "name": "mockd",
+            "x_auth_token": self.access_token,
+            "refresh_token": self.refresh_token,
+        });
+        // the client strips the first 16 bytes of the decrypted login response
+        let mut payload = vec![b'A'; 16];
+        payload.extend_from_slice(userauth.to_string().as_bytes());
+        json!({ "msg_encrypt": aes_encrypt(&payload) })
+    }
+
+    fn refresh(&mut self, json: &Value) -> ApiResult {
+        if json["refresh_token"].as_str() != Some(self.refresh_token.as_str()) {
+            return Err((UNAUTHORIZED, "invalid refresh token".to_owned()));
+        }
+        self.access_token = new_id().simple().to_string();
+        self.refresh_token = new_id().simple().to_string();
+        let refreshed = json!({
+            "x_auth_token": self.access_token,
+            "refresh_token": self.refresh_token,
+        });
+        Ok(json!({ "msg_encrypt": aes_encrypt(refreshed.to_string().as_bytes()) }))
+    }
+
+    fn used_space(&self) -> u64 {
+        self.nodes.values().map(|n| n.bytes).sum()
+    }
+
+    fn userinfo(&self) -> Value {
+        let used = self.used_space();
+        json!({
+            "user_type": 1,
+            "user_group_id": 1,
+            "user_number": "mockd",
+            "gid": "1",
+            "username": "mockd",
+            "name": "mockd",
+            "email": "",
+            "mobile": "",
+            "profile": "",
+            "gender": 0,
+            "avatar": "",
+            "total_space": self.total_space.to_string(),
+            "used_space": used.to_string(),
+            "user_file_count": self.nodes.len(),
+            "user_share_count": 0,
+            "user_group_count": 0,
+            "is_backup_file": false,
+        })
+    }
+
+    fn list(&self, fid: &str, query: &HashMap<String, String>) -> ApiResult {
+        let disk = query
+            .get("disk_type")
+            .map(String::as_str)
+            .unwrap_or("cloud");
+        let parent = if fid == "R_0" { ROOT } else { fid };
+        if !self.is_folder(parent) {
+            return Err(not_found(fid));
+        }
+        let datas: Vec<Value> = self
+            .nodes
+            .iter()
+            .filter(|(_, n)| match disk {
+                // recycled items keep their old parent
+                "recycle" => n.disk == "recycle",
+                _ => n.parent == parent && n.disk == disk,
+            })
+            .map(|(id, n)| {
+                json!({
+                    "bytes": if n.folder { json!("") } else { json!(n.bytes) },
+                    "file_ext": n.ext,
+                    "file_type": if n.folder { "folder" } else { n.ext.as_str() },
+                    "hash": n.hash,
+                    "last_update_date": n.updated,
+                    "name": n.name,
+                    "number": id,
+                    "parent_number": n.parent,
+                    "type": if n.folder { "folder" } else { "file" },
+                })
+            })
+            .collect();
+        Ok(json!({ "datas": datas }))
+    }
+
+    fn upload_begin(&mut self, parent: &str, query: &HashMap<String, String>) -> ApiResult {
+        if !self.is_folder(parent) {
+            return Err(not_found(parent));
+        }
+        let name = query
+            .get("file_name")
+            .ok_or((BAD_REQUEST, "missing file_name".to_owned()))?;
+        let bytes: u64 = query
+            .get("byte")
+            .and_then(|b| b.parse().ok())
+            .ok_or((BAD_REQUEST, "missing byte".to_owned()))?;
+        if bytes == 0 {
+            return Err((BAD_REQUEST, "empty files cannot be uploaded".to_owned()));
+        }
+        if self.used_space() + bytes > self.total_space {
+            return Err((INSUFFICIENT_SPACE, "insufficient space".to_owned()));
+        }
+        let token = new_id().simple().to_string();
+        let chunks = bytes.div_ceil(self.chunk_size);
+        let params: Vec<Value> = (0..chunks)
+            .map(|i| {
+                json!([
+                    { "key": "part_number", "request_type": "query", "value": (i + 1).to_string() },
+                    { "key": "url", "request_type": "url", "value": format!("{}/mockd/upload/{}/{}", self.base_url, token, i) },
+                    { "key": "method", "request_type": "method", "value": "PUT" },
+                ])
+            })
+            .collect();
+        self.uploads.insert(
+            token.clone(),
+            Upload {
+                parent: parent.to_owned(),
+                name: name.clone(),
+                bytes,
+                chunks,
+            },
+        );
+        Ok(json!({
+            "upload_token": token,
+            "upload_chunk_size": self.chunk_size.to_string(),
+            "upload_params": params,
+        }))
+    }
+
+    fn upload_part(&mut self, part: &str, data: &[u8]) -> Result<(), String> {
+        let (token, idx) = part.split_once('/').ok_or("bad upload url")?;
+        let upload = self.uploads.get(token).ok_or("unknown upload token")?;
+        let idx: u64 = idx.parse().map_err(|_| "bad part index")?;
+        if idx >= upload.chunks {
+            return Err("part index out of range".to_owned());
+        }
+        std::fs::write(
+            self.root.join("uploads").join(format!("{}.{}", token, idx)),
+            data,
+        )
+        .map_err(|e| e.to_string())?;
+        self.parts += 1;
+        Ok(())
+    }
+
+    fn upload_complete(&mut self, json: &Value) -> ApiResult {
+        let token = json["upload_token"]
+            .as_str()
+            .ok_or((BAD_REQUEST, "missing upload_token".to_owned()))?;
+        let upload = self
+            .uploads
+            .get(token)
+            .ok_or_else(|| not_found("upload token"))?;
+        let mut content = Vec::new();
+        for idx in 0..upload.chunks {
+            let part = self.root.join("uploads").join(format!("{}.{}", token, idx));
+            let data = std::fs::read(&part)
+                .map_err(|_| (BAD_REQUEST, format!("part {} is missing", idx)))?;
+            content.extend_from_slice(&data);
+        }
+        if content.len() as u64 != upload.bytes {
+            return Err((
+                BAD_REQUEST,
+                format!("expected {} bytes, got {}", upload.bytes, content.len()),
+            ));
+        }
+        let upload = self.uploads.remove(token).unwrap();
+        for idx in 0..upload.chunks {
+            let _ =
+                std::fs::remove_file(self.root.join("uploads").join(format!("{}.{}", token, idx)));
+        }
+        if self.corrupt_uploads {
+            content[0] ^= 0xff;
+        }
+        let id = self.insert_node(&upload.parent, &upload.name, false, Some(&content));
+        Ok(json!({ "number": id }))
+    }
+
+    fn download(&mut self, json: &Value) -> ApiResult {
+        self.url_requests += 1;
+        let mut urls = serde_json::Map::new();
+        for fid in json["files_list"].as_array().into_iter().flatten() {
+            let fid = fid.as_str().unwrap_or_default();
+            if self.nodes.get(fid).map(|n| !n.folder).unwrap_or(false) {
+                urls.insert(
+                    fid.to_owned(),
+                    json!(format!(
+                        "{}/mockd/download/{}?key={}",
+                        self.base_url, fid, self.url_key
+                    )),
+                );
+            }
+        }
+        Ok(Value::Object(urls))
+    }
+
+    fn operation(&mut self, json: &Value) -> ApiResult {
+        let action = json["action"].as_str().unwrap_or_default();
+        let dst = json["number"].as_str().unwrap_or_default().to_owned();
+        for file in json["files_list"].as_array().into_iter().flatten() {
+            let id = file["number"].as_str().unwrap_or_default().to_owned();
+            if !self.nodes.contains_key(&id) {
+                return Err(not_found(&id));
+            }
+            match action {
+                "move" | "copy" if !self.is_folder(&dst) => return Err(not_found(&dst)),
+                "move" => {
+                    let name = self.nodes[&id].full_name();
+                    if self.child_named(&dst, "cloud", &name).is_some() {
+                        return Err((CONFLICT, format!("{} already exists", name)));
+                    }
+                    self.nodes.get_mut(&id).unwrap().parent = dst.clone();
+                }
+                "copy" => {
+                    self.copy(&id, &dst);
+                }
+                "recycle" | "delete" => {
+                    if self.nodes[&id].disk == "recycle" {
+                        self.purge(&id);
+                    } else {
+                        self.nodes.get_mut(&id).unwrap().disk = "recycle".to_owned();
+                    }
+                }
+                "restore" => {
+                    self.nodes.get_mut(&id).unwrap().disk = "cloud".to_owned();
+                }
+                _ => return Err((BAD_REQUEST, format!("unknown action {}", action))),
+            }
+        }
+        Ok(json!({}))
+    }
+
+    fn copy(&mut self, id: &str, dst: &str) -> String {
+        let node = self.nodes[id].clone();
+        let new_id = if node.folder {
+            self.insert_node(dst, &node.full_name(), true, None)
+        } else {
+            let content = std::fs::read(self.object_path(id)).unwrap_or_default();
+            self.insert_node(dst, &node.full_name(), false, Some(&content))
+        };
+        let children: Vec<String> = self
+            .nodes
+            .iter()
+            .filter(|(_, n)| n.parent == id)
+            .map(|(child, _)| child.clone())
+            .collect();
+        for child in children {
+            self.copy(&child, &new_id);
+        }
+        new_id
+    }
+
+    fn purge(&mut self, id: &str) {
+        let children: Vec<String> = self
+            .nodes
+            .iter()
+            .filter(|(_, n)| n.parent == id)
+            .map(|(child, _)| child.clone())
+            .collect();
+        for child in children {
+            self.purge(&child);
+        }
+        self.nodes.remove(id);
+        let _ = std::fs::remove_file(self.object_path(id));
+    }
+
+    fn rename(&mut self, json: &Value, with_ext: bool) -> ApiResult {
+        let id = json["number"].as_str().unwrap_or_default();
+        let name = json["name"]
+            .as_str()
+            .ok_or((BAD_REQUEST, "missing name".to_owned()))?;
+        let node = self.nodes.get(id).ok_or_else(|| not_found(id))?;
+        let (new_name, new_ext) = if with_ext {
+            split_name(name)
+        } else {
+            (name.to_owned(), node.ext.clone())
+        };
+        let full_name = Node::full(&new_name, &new_ext);
+        if self
+            .child_named(&node.parent, &node.disk, &full_name)
+            .is_some()
+        {
+            return Err((CONFLICT, format!("{} already exists", full_name)));
+        }
+        let node = self.nodes.get_mut(id).unwrap();
+        node.name = new_name;
+        node.ext = new_ext;
+        node.updated = now();
+        Ok(json!({}))
+    }
+
+    fn mkdir(&mut self, json: &Value) -> ApiResult {
+        let parent = json["number"].as_str().unwrap_or(ROOT).to_owned();
+        if !self.is_folder(&parent) {
+            return Err(not_found(&parent));
+        }
+        for name in json["paramslist"].as_array().into_iter().flatten() {
+            let name = name.as_str().unwrap_or_default();
+            if self.child_named(&parent, "cloud", name).is_some() {
+                return Err((CONFLICT, format!("{} already exists", name)));
+            }
+            self.insert_node(&parent, name, true, None);
+        }
+        Ok(json!({}))
+    }
+}
@@ -0,0 +1,267 @@
+// Background polling of rec for changes made by other clients (e.g. the web interface)
+use crate::cache::Cache;
+use crate::client::RecClient;
+use crate::fid::Fid;
+use crate::fidmap::FidMap;
+use fuse_mt::FileType;
+use log::{debug, info, warn};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+// folders listed by one poll at most, most recently used first
+const MAX_DIRS: usize = 32;
+// how often the refresher saves the metadata file, besides on unmount
+const SAVE_INTERVAL: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub dirs: usize,
+    pub added: usize,
+    pub removed: usize,
+    pub modified: usize,
+}
+
+pub struct Poller {
+    stop: Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Poller {
+    pub fn spawn(
+        interval: Duration,
+        client: Arc<RecClient>,
+        fid_map: Arc<RwLock<FidMap>>,
+        cache: Arc<Cache>,
+    ) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            // the sender is dropped by stop()
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                poll(&client, &fid_map, &cache);
+            }
+        });
+        Self { stop, thread }
+    }
+
+    pub fn stop(self) {
+        drop(self.stop);
+        let _ = self.thread.join();
+    }
+}
+
+// list cached folders again, update their listings and drop local copies of changed files
+// pinned files are downloaded again
+pub fn poll(client: &RecClient, fid_map: &RwLock<FidMap>, cache: &Cache) -> Summary {
+    let mut summary = Summary::default();
+    let dirs = fid_map.read().unwrap().listed_dirs(MAX_DIRS);
+    for fid in dirs {
+        poll_dir(client, fid_map, cache, fid, &mut summary);
+    }
+    summary.log();
+    for fid in cache.pinned() {
+        sync_pinned(client, fid_map, cache, fid);
+    }
+    summary
+}
+
+// download a pinned file, or everything in a pinned folder, unless it is cached already
+pub fn sync_pinned(client: &RecClient, fid_map: &RwLock<FidMap>, cache: &Cache, fid: Fid) {
+    if fid.is_created() {
+        return;
+    }
+    let item = match fid_map.read().unwrap().get_item(&fid) {
+        Some(item) => item,
+        None => {
+            debug!("pin: {} is not known, skipped", fid);
+            return;
+        }
+    };
+    if item.ftype == FileType::Directory {
+        let (listed, fresh) = {
+            let map = fid_map.read().unwrap();
+            let listed = map.get_listing(&fid).is_some_and(|l| l.children.is_some());
+            (listed, map.is_fresh(&fid))
+        };
+        if !listed {
+            let items = match client.list(fid) {
+                Ok(items) => items,
+                Err(e) => {
+                    warn!("pin: failed to list {}: {}", item.name, e);
+                    return;
+                }
+            };
+            fid_map.write().unwrap().set_children(fid, items);
+        } else if !fresh {
+            let mut summary = Summary::default();
+            poll_dir(client, fid_map, cache, fid, &mut summary);
+            summary.log();
+        }
+        let children = fid_map
+            .read()
+            .unwrap()
+            .get_listing(&fid)
+            .and_then(|l| l.children.clone())
+            .unwrap_or_default();
+        // one request for the urls of the files to download, instead of one per file
+        let files: Vec<Fid> = children
+            .iter()
+            .filter(|c| c.ftype == FileType::RegularFile && !c.fid.is_created())
+            .filter(|c| cache.contains(c.fid).is_none())
+            .map(|c| c.fid)
+            .collect();
+        if let Err(e) = client.resolve_download_urls(&files) {
+            warn!("pin: failed to get download urls in {}: {}", item.name, e);
+        }
+        for child in children {
+            sync_pinned(client, fid_map, cache, child.fid);
+        }
+    } else {
+        cache.validate(&item);
+        if cache.contains(fid).is_none() {
+            info!("pin: download {}", item.name);
+            if let Err(e) = cache.fetch(client, &item) {
+                warn!("pin: failed to download {}: {}", item.name, e);
+            }
+        }
+    }
+}
+
+fn poll_dir(
+    client: &RecClient,
+    fid_map: &RwLock<FidMap>,
+    cache: &Cache,
+    fid: Fid,
+    summary: &mut Summary,
+) {
+    let items = match client.list(fid) {
+        Ok(items) => items,
+        Err(e) => {
+            warn!("poll: failed to list {}: {}", fid, e);
+            return;
+        }
+    };
+    let mut changed = vec![];
+    {
+        let mut map = fid_map.write().unwrap();
+        let old = match map.get_listing(&fid).and_then(|l| l.children.clone()) {
+            Some(old) => old,
+            None => return, // forgotten in the meantime
+        };
+        // created files are not on rec yet
+        let old: Vec<_> = old.into_iter().filter(|i| !i.fid.is_created()).collect();
+        for item in items.iter() {
+            match old.iter().find(|o| o.name == item.name) {
+                None => {
+                    debug!("poll: {} added in {}", item.name, fid);
+                    summary.added += 1;
+                }
+                Some(o)
+                    if o.fid != item.fid
+                        || (item.ftype == FileType::RegularFile
+                            && (o.version() != item.version() || o.bytes != item.bytes)) =>
+                {
+                    debug!("poll: {} modified in {}", item.name, fid);
+                    summary.modified += 1;
+                    changed.push(item.clone());
+                }
+                Some(_) => {}
+            }
+        }
+        for o in old
+            .iter()
+            .filter(|o| !items.iter().any(|i| i.name == o.name))
+        {
+            debug!("poll: {} removed from {}", o.name, fid);
+            summary.removed += 1;
+            if o.ftype == FileType::Directory {
+                map.forget(&o.fid);
+            }
+        }
+        map.set_children(fid, items);
+    }
+    summary.dirs += 1;
+    for item in changed.iter() {
+        cache.validate(item);
+    }
+}
+
+impl Summary {
+    fn log(&self) {
+        if self.added + self.removed + self.modified > 0 {
+            info!(
+                "poll: {} added, {} removed, {} modified in {} folders",
+                self.added, self.removed, self.modified, self.dirs
+            );
+        } else {
+            debug!("poll: no changes in {} folders", self.dirs);
+        }
+    }
+}
+
+// revalidates listings restored from the metadata file as they are used, and saves the
+// metadata file from time to time
+pub struct Refresher {
+    queue: Sender<Fid>,
+    queued: Arc<Mutex<HashSet<Fid>>>,
+    thread: JoinHandle<()>,
+}
+
+impl Refresher {
+    pub fn spawn(
+        metadata: PathBuf,
+        client: Arc<RecClient>,
+        fid_map: Arc<RwLock<FidMap>>,
+        cache: Arc<Cache>,
+    ) -> Self {
+        let (queue, queued_fids) = mpsc::channel::<Fid>();
+        let queued = Arc::new(Mutex::new(HashSet::new()));
+        let thread = {
+            let queued = queued.clone();
+            std::thread::spawn(move || {
+                // a busy queue must not put off the save, so the deadline is kept across refreshes
+                let mut saved = Instant::now();
+                loop {
+                    let timeout = SAVE_INTERVAL.saturating_sub(saved.elapsed());
+                    match queued_fids.recv_timeout(timeout) {
+                        Ok(fid) => {
+                            queued.lock().unwrap().remove(&fid);
+                            let mut summary = Summary::default();
+                            poll_dir(&client, &fid_map, &cache, fid, &mut summary);
+                            summary.log();
+                        }
+                        Err(RecvTimeoutError::Timeout) => {}
+                        // the sender is dropped by stop()
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                    if saved.elapsed() >= SAVE_INTERVAL {
+                        if let Err(e) = fid_map.read().unwrap().save(&metadata) {
+                            warn!("Failed to save {}: {}", metadata.display(), e);
+                        }
+                        saved = Instant::now();
+                    }
+                }
+            })
+        };
+        Self {
+            queue,
+            queued,
+            thread,
+        }
+    }
+
+    // list fid again soon, unless it is queued already
+    pub fn push(&self, fid: Fid) {
+        if self.queued.lock().unwrap().insert(fid) {
+            let _ = self.queue.send(fid);
+        }
+    }
+
+    pub fn stop(self) {
+        drop(self.queue);
+        let _ = self.thread.join();
+    }
+}
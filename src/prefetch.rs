@@ -0,0 +1,201 @@
+// Download in background the next files of a folder which is read file after file,
+// as by cp -r or tar, so that each of them does not wait for rec when it is opened
+use crate::cache::Cache;
+use crate::client::list::RecListItem;
+use crate::client::RecClient;
+use crate::fid::Fid;
+use fuse_mt::FileType;
+use log::{debug, info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+// files opened in listing order after opendir() before the next ones are prefetched
+const SEQUENTIAL: usize = 2;
+// folders whose traversal is followed at the same time
+const MAX_DIRS: usize = 64;
+
+pub struct Prefetcher {
+    state: Arc<(Mutex<PrefetchState>, Condvar)>,
+    worker: Mutex<Option<JoinHandle<()>>>,
+    cache: Arc<Cache>,
+}
+
+#[derive(Default)]
+struct PrefetchState {
+    dirs: HashMap<Fid, Traversal>,
+    queued: VecDeque<RecListItem>,
+    fetching: bool,
+    stop: bool,
+}
+
+// how a folder has been read since it was opened
+struct Traversal {
+    opened: Instant,
+    // index in the listing of the last file opened
+    last: Option<usize>,
+    // files opened one after another in listing order
+    run: usize,
+    // files before this index are prefetched already
+    ahead: usize,
+}
+
+impl Prefetcher {
+    pub fn start(client: Arc<RecClient>, cache: Arc<Cache>) -> Self {
+        let state = Arc::new((Mutex::new(PrefetchState::default()), Condvar::new()));
+        let worker = {
+            let state = state.clone();
+            let cache = cache.clone();
+            std::thread::spawn(move || work(&state, &client, &cache))
+        };
+        Self {
+            state,
+            worker: Mutex::new(Some(worker)),
+            cache,
+        }
+    }
+
+    // a folder is opened: what is opened in it next may be a traversal
+    pub fn opened_dir(&self, fid: Fid) {
+        let mut state = self.state.0.lock().unwrap();
+        if state.dirs.len() >= MAX_DIRS && !state.dirs.contains_key(&fid) {
+            let oldest = state
+                .dirs
+                .iter()
+                .min_by_key(|(_, t)| t.opened)
+                .map(|(f, _)| *f);
+            if let Some(oldest) = oldest {
+                state.dirs.remove(&oldest);
+            }
+        }
+        state.dirs.insert(
+            fid,
+            Traversal {
+                opened: Instant::now(),
+                last: None,
+                run: 0,
+                ahead: 0,
+            },
+        );
+    }
+
+    // a file of parent is opened for reading
+    // once it is a traversal, the files after it are queued, as many as fit in the limits
+    // of the cache config
+    pub fn opened(&self, parent: Fid, fid: Fid, children: &[RecListItem]) {
+        let config = self.cache.config();
+        if config.prefetch_files == 0 {
+            return;
+        }
+        let idx = match children.iter().position(|c| c.fid == fid) {
+            Some(idx) => idx,
+            None => return,
+        };
+        let (lock, cvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        let traversal = match state.dirs.get_mut(&parent) {
+            Some(traversal) => traversal,
+            None => return,
+        };
+        traversal.run = match traversal.last {
+            Some(last) if idx <= last => 1,
+            _ => traversal.run + 1,
+        };
+        traversal.last = Some(idx);
+        if traversal.run < SEQUENTIAL {
+            return;
+        }
+        let mut size = 0;
+        let window: Vec<(usize, &RecListItem)> = children
+            .iter()
+            .enumerate()
+            .skip(idx + 1)
+            .filter(|(_, c)| c.ftype == FileType::RegularFile && !c.fid.is_created())
+            .take(config.prefetch_files)
+            .take_while(|(_, c)| {
+                size += c.bytes as u64;
+                size <= config.prefetch_size
+            })
+            .collect();
+        let start = traversal.ahead.max(idx + 1);
+        if let Some((end, _)) = window.last() {
+            traversal.ahead = start.max(end + 1);
+        }
+        let items: Vec<RecListItem> = window
+            .into_iter()
+            .filter(|(i, c)| *i >= start && self.cache.contains(c.fid).is_none())
+            .map(|(_, c)| c.clone())
+            .collect();
+        if !items.is_empty() {
+            debug!(
+                "prefetch: {} files after {} in {}",
+                items.len(),
+                fid,
+                parent
+            );
+            state.queued.extend(items);
+            cvar.notify_all();
+        }
+    }
+
+    // wait until every queued file is downloaded
+    #[cfg(test)]
+    pub fn wait_all(&self) {
+        let (lock, cvar) = &*self.state;
+        let _state = cvar
+            .wait_while(lock.lock().unwrap(), |s| !s.queued.is_empty() || s.fetching)
+            .unwrap();
+    }
+
+    // drop the queued files and stop the worker
+    pub fn stop(&self) {
+        {
+            let (lock, cvar) = &*self.state;
+            let mut state = lock.lock().unwrap();
+            state.queued.clear();
+            state.stop = true;
+            cvar.notify_all();
+        }
+        if let Some(worker) = self.worker.lock().unwrap().take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn work(state: &(Mutex<PrefetchState>, Condvar), client: &RecClient, cache: &Cache) {
+    let (lock, cvar) = state;
+    loop {
+        let items: Vec<RecListItem> = {
+            let mut state = lock.lock().unwrap();
+            state.fetching = false;
+            cvar.notify_all();
+            while state.queued.is_empty() && !state.stop {
+                state = cvar.wait(state).unwrap();
+            }
+            if state.stop {
+                return;
+            }
+            state.fetching = true;
+            state.queued.drain(..).collect()
+        };
+        // the urls of all of them in one request
+        let fids: Vec<Fid> = items.iter().map(|i| i.fid).collect();
+        if let Err(e) = client.resolve_download_urls(&fids) {
+            warn!("prefetch: failed to get download urls: {}", e);
+        }
+        for item in items {
+            if lock.lock().unwrap().stop {
+                return;
+            }
+            cache.validate(&item);
+            if cache.contains(item.fid).is_some() {
+                continue;
+            }
+            info!("prefetch: download {}", item.name);
+            if let Err(e) = cache.fetch(client, &item) {
+                warn!("prefetch: failed to download {}: {}", item.name, e);
+            }
+        }
+    }
+}
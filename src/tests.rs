@@ -0,0 +1,1429 @@
+// Integration tests driving RecFs against the local mock server in mockd.rs.
+// Requests are issued directly through the FilesystemMT trait, as fuse_mt would do after mount.
+
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use fuse_mt::{FileType, FilesystemMT, RequestInfo, Xattr};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+
+use crate::cache::{Cache, CacheConfig};
+use crate::client::auth::{RecAuth, Token};
+use crate::client::{RecClient, RecConfig, RetryPolicy};
+use crate::fidmap::Ttl;
+use crate::fs::RecFs;
+use crate::mockd::MockServer;
+
+struct Mounted {
+    mock: MockServer,
+    fs: RecFs,
+    dir: PathBuf,
+    ttl: Ttl,
+}
+
+impl Mounted {
+    fn new() -> Self {
+        Self::with_cache(CacheConfig::default())
+    }
+
+    fn with_cache(config: CacheConfig) -> Self {
+        Self::with_options(config, Ttl::default())
+    }
+
+    fn with_options(config: CacheConfig, ttl: Ttl) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "recfs-test-{}",
+            thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(10)
+                .map(char::from)
+                .collect::<String>()
+        ));
+        let mock = MockServer::start("127.0.0.1:0", &dir.join("remote")).unwrap();
+        let fs = mount(&mock, &dir, config, ttl.clone());
+        Self { mock, fs, dir, ttl }
+    }
+
+    // unmount, and mount again with the same cache folder
+    fn remount(&mut self, config: CacheConfig) {
+        self.fs.destroy();
+        self.fs = mount(&self.mock, &self.dir, config, self.ttl.clone());
+    }
+
+    fn readdir(&self, path: &str) -> Vec<String> {
+        let (fh, _) = self.fs.opendir(req(), Path::new(path), 0).unwrap();
+        let mut names: Vec<String> = self
+            .fs
+            .readdir(req(), Path::new(path), fh)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name.to_string_lossy().into_owned())
+            .collect();
+        self.fs.releasedir(req(), Path::new(path), fh, 0).unwrap();
+        names.sort();
+        names
+    }
+
+    fn read(&self, path: &str) -> Vec<u8> {
+        let (fh, _) = self
+            .fs
+            .open(req(), Path::new(path), libc::O_RDONLY as u32)
+            .unwrap();
+        let data = self.fs.read_data(fh, 0, u32::MAX).unwrap();
+        self.fs
+            .release(req(), Path::new(path), fh, libc::O_RDONLY as u32, 0, true)
+            .unwrap();
+        data
+    }
+
+    fn xattr(&self, path: &str, name: &str) -> String {
+        match self
+            .fs
+            .getxattr(req(), Path::new(path), OsStr::new(name), 256)
+        {
+            Ok(Xattr::Data(data)) => String::from_utf8(data).unwrap(),
+            res => panic!("getxattr {} {}: {:?}", path, name, res.err()),
+        }
+    }
+
+    fn write_new(&self, parent: &str, name: &str, data: &[u8]) -> fuse_mt::ResultEmpty {
+        let created = self
+            .fs
+            .create(
+                req(),
+                Path::new(parent),
+                OsStr::new(name),
+                0o600,
+                libc::O_WRONLY as u32,
+            )
+            .unwrap();
+        let path = Path::new(parent).join(name);
+        self.fs
+            .write(
+                req(),
+                &path,
+                created.fh,
+                0,
+                data.to_vec(),
+                libc::O_WRONLY as u32,
+            )
+            .unwrap();
+        self.fs
+            .release(req(), &path, created.fh, libc::O_WRONLY as u32, 0, true)?;
+        self.fs.wait_uploads();
+        Ok(())
+    }
+}
+
+impl Drop for Mounted {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn mount(mock: &MockServer, dir: &Path, config: CacheConfig, ttl: Ttl) -> RecFs {
+    RecFs::with_client(
+        client(mock, mock.api_url()),
+        Cache::new(dir.join("cache"), config).unwrap(),
+        ttl,
+        2,
+    )
+}
+
+fn client(mock: &MockServer, api_url: String) -> RecClient {
+    let (access_token, refresh_token) = mock.tokens();
+    let mut client = RecClient::new(RecConfig {
+        api_url,
+        retry: RetryPolicy {
+            attempts: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+        },
+        ..Default::default()
+    });
+    client.set_auth(RecAuth {
+        token: Some(Token {
+            access_token,
+            refresh_token,
+        }),
+    });
+    client
+}
+
+fn req() -> RequestInfo {
+    RequestInfo {
+        unique: 0,
+        uid: 0,
+        gid: 0,
+        pid: 0,
+    }
+}
+
+#[test]
+fn test_readdir_getattr() {
+    let m = Mounted::new();
+    let docs = m.mock.add_folder("0", "docs");
+    m.mock.add_file(&docs, "a.txt", b"hello");
+
+    assert_eq!(m.readdir("/"), vec!["?Backup", "?Recycle", "docs"]);
+    assert_eq!(m.readdir("/docs"), vec!["a.txt"]);
+    let (_, attr) = m.fs.getattr(req(), Path::new("/docs/a.txt"), None).unwrap();
+    assert_eq!(attr.kind, FileType::RegularFile);
+    assert_eq!(attr.size, 5);
+    assert_eq!(
+        m.fs.getattr(req(), Path::new("/docs/b.txt"), None)
+            .unwrap_err(),
+        libc::ENOENT
+    );
+}
+
+#[test]
+fn test_read() {
+    let m = Mounted::new();
+    m.mock.add_file("0", "a.bin", &[7u8; 10000]);
+    assert_eq!(m.read("/a.bin"), vec![7u8; 10000]);
+}
+
+#[test]
+fn test_create_upload() {
+    let m = Mounted::new();
+    m.mock.set_chunk_size(1000);
+    let data: Vec<u8> = (0..4500u32).map(|i| i as u8).collect();
+    m.write_new("/", "new.bin", &data).unwrap();
+
+    let fid = m.mock.find("0", "new.bin").unwrap();
+    assert_eq!(m.mock.content(&fid).unwrap(), data);
+    assert_eq!(m.readdir("/"), vec!["?Backup", "?Recycle", "new.bin"]);
+}
+
+#[test]
+fn test_parallel_upload() {
+    let m = Mounted::new();
+    m.mock.set_chunk_size(1000);
+    let data: Vec<u8> = (0..9500u32).map(|i| (i * 7) as u8).collect();
+
+    // chunks are sent 4 at a time, and put together in order
+    m.mock.hold_uploads(true);
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            while m.mock.held() < 4 {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            std::thread::sleep(Duration::from_millis(50));
+            assert_eq!(m.mock.held(), 4);
+            m.mock.hold_uploads(false);
+        });
+        m.write_new("/", "big.bin", &data).unwrap();
+    });
+    assert_eq!(m.mock.parts(), 10);
+    let id = m.mock.find("0", "big.bin").unwrap();
+    assert_eq!(m.mock.content(&id).unwrap(), data);
+}
+
+#[test]
+fn test_mkdir_rename_unlink() {
+    let m = Mounted::new();
+    m.mock.add_file("0", "a.txt", b"a");
+    m.fs.mkdir(req(), Path::new("/"), OsStr::new("dir"), 0o700)
+        .unwrap();
+    m.fs.rename(
+        req(),
+        Path::new("/"),
+        OsStr::new("a.txt"),
+        Path::new("/dir"),
+        OsStr::new("a.txt"),
+    )
+    .unwrap();
+    m.fs.rename(
+        req(),
+        Path::new("/dir"),
+        OsStr::new("a.txt"),
+        Path::new("/dir"),
+        OsStr::new("b.md"),
+    )
+    .unwrap();
+    assert_eq!(m.readdir("/dir"), vec!["b.md"]);
+
+    m.fs.unlink(req(), Path::new("/dir"), OsStr::new("b.md"))
+        .unwrap();
+    assert!(m.readdir("/dir").is_empty());
+    assert_eq!(m.readdir("/?Recycle"), vec!["b.md"]);
+}
+
+#[test]
+fn test_link_copies() {
+    let m = Mounted::new();
+    let src = m.mock.add_folder("0", "src");
+    m.mock.add_folder("0", "dst");
+    m.mock.add_file(&src, "a.txt", b"copy me");
+
+    m.fs.link(
+        req(),
+        Path::new("/src/a.txt"),
+        Path::new("/dst"),
+        OsStr::new("a.txt"),
+    )
+    .unwrap();
+    assert_eq!(m.read("/dst/a.txt"), b"copy me");
+    assert_eq!(m.read("/src/a.txt"), b"copy me");
+}
+
+#[test]
+fn test_errno() {
+    let m = Mounted::new();
+    let dir = m.mock.add_folder("0", "dir");
+    m.mock.add_file(&dir, "a.txt", b"a");
+
+    let mkdir = m.fs.mkdir(req(), Path::new("/"), OsStr::new("dir"), 0o700);
+    assert_eq!(mkdir.unwrap_err(), libc::EEXIST);
+    let rmdir = m.fs.rmdir(req(), Path::new("/"), OsStr::new("dir"));
+    assert_eq!(rmdir.unwrap_err(), libc::ENOTEMPTY);
+
+    // failed uploads are reported through the root folder
+    m.mock.set_total_space(4);
+    m.write_new("/", "big.bin", b"12345").unwrap();
+    assert_eq!(m.mock.find("0", "big.bin"), None);
+    let status = m.xattr("/", "user.recfs.uploads");
+    assert!(status.contains("failed: 1\nbig.bin: "), "{}", status);
+}
+
+#[test]
+fn test_retry() {
+    let m = Mounted::new();
+    m.mock.add_file("0", "a.txt", b"retry");
+    m.mock.add_folder("0", "d");
+
+    // idempotent requests are retried
+    m.mock.fail_next(2, 502);
+    assert_eq!(m.readdir("/"), vec!["?Backup", "?Recycle", "a.txt", "d"]);
+    m.mock.fail_next(2, 503);
+    assert_eq!(m.read("/a.txt"), b"retry");
+    // until running out of attempts
+    m.mock.fail_next(3, 502);
+    assert_eq!(
+        m.fs.getattr(req(), Path::new("/d"), None).unwrap_err(),
+        libc::EIO
+    );
+
+    // mkdir may have been done by rec before a 502, so it is not retried
+    m.mock.fail_next(1, 502);
+    let mkdir = m.fs.mkdir(req(), Path::new("/"), OsStr::new("dir"), 0o700);
+    assert_eq!(mkdir.unwrap_err(), libc::EIO);
+    // but 503 means rec has not processed it
+    m.mock.fail_next(1, 503);
+    m.fs.mkdir(req(), Path::new("/"), OsStr::new("dir"), 0o700)
+        .unwrap();
+}
+
+#[test]
+fn test_write_back() {
+    let m = Mounted::new();
+    let old = m.mock.add_file("0", "a.txt", b"hello world");
+
+    let path = Path::new("/a.txt");
+    let (fh, _) = m.fs.open(req(), path, libc::O_RDWR as u32).unwrap();
+    m.fs.write(req(), path, fh, 6, b"rec!!".to_vec(), libc::O_RDWR as u32)
+        .unwrap();
+    let (_, attr) = m.fs.getattr(req(), path, Some(fh)).unwrap();
+    assert_eq!(attr.size, 11);
+    m.fs.release(req(), path, fh, libc::O_RDWR as u32, 0, true)
+        .unwrap();
+
+    let new = m.mock.find("0", "a.txt").unwrap();
+    assert_ne!(new, old);
+    assert_eq!(m.mock.content(&new).unwrap(), b"hello rec!!");
+    assert_eq!(m.mock.disk(&old).unwrap(), "recycle");
+    assert_eq!(m.readdir("/"), vec!["?Backup", "?Recycle", "a.txt"]);
+    assert_eq!(m.read("/a.txt"), b"hello rec!!");
+
+    m.fs.truncate(req(), path, None, 5).unwrap();
+    let new = m.mock.find("0", "a.txt").unwrap();
+    assert_eq!(m.mock.content(&new).unwrap(), b"hello");
+
+    // echo bye > b.txt: the kernel opens it, then truncates it without fh
+    let old = m.mock.add_file("0", "b.txt", b"old content");
+    let path = Path::new("/b.txt");
+    let parts = m.mock.parts();
+    let (fh, _) = m.fs.open(req(), path, libc::O_WRONLY as u32).unwrap();
+    m.fs.truncate(req(), path, None, 0).unwrap();
+    // nothing is uploaded until the data is there
+    assert_eq!(m.mock.find("0", "b.txt").unwrap(), old);
+    m.fs.write(req(), path, fh, 0, b"bye\n".to_vec(), libc::O_WRONLY as u32)
+        .unwrap();
+    m.fs.release(req(), path, fh, libc::O_WRONLY as u32, 0, true)
+        .unwrap();
+    let new = m.mock.find("0", "b.txt").unwrap();
+    assert_eq!(m.mock.content(&new).unwrap(), b"bye\n");
+    // uploaded once, and the old copy is recycled
+    assert_eq!(m.mock.parts(), parts + 1);
+    assert_eq!(m.mock.disk(&old).unwrap(), "recycle");
+
+    // an emptied file cannot be stored on rec, so the old content stays
+    let (fh, _) = m.fs.open(req(), path, libc::O_WRONLY as u32).unwrap();
+    m.fs.truncate(req(), path, None, 0).unwrap();
+    let release =
+        m.fs.release(req(), path, fh, libc::O_WRONLY as u32, 0, true);
+    assert_eq!(release.unwrap_err(), libc::EINVAL);
+    assert_eq!(m.mock.find("0", "b.txt").unwrap(), new);
+    assert_eq!(m.read("/b.txt"), b"bye\n");
+
+    // O_TRUNC does not download the old content
+    let downloaded = m.mock.downloaded();
+    m.mock.add_file("0", "c.txt", b"old content");
+    let path = Path::new("/c.txt");
+    let flags = (libc::O_WRONLY | libc::O_TRUNC) as u32;
+    let (fh, _) = m.fs.open(req(), path, flags).unwrap();
+    m.fs.write(req(), path, fh, 0, b"new".to_vec(), flags)
+        .unwrap();
+    m.fs.release(req(), path, fh, flags, 0, true).unwrap();
+    let new = m.mock.find("0", "c.txt").unwrap();
+    assert_eq!(m.mock.content(&new).unwrap(), b"new");
+    assert_eq!(m.mock.downloaded(), downloaded);
+}
+
+#[test]
+fn test_upload_queue() {
+    let m = Mounted::new();
+    let uploading = || {
+        while m.mock.uploading() == 0 {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let status = m.xattr("/", "user.recfs.uploads");
+        assert!(status.contains("uploading: 1\n"), "{}", status);
+    };
+    let create = |name: &str| {
+        let created =
+            m.fs.create(
+                req(),
+                Path::new("/"),
+                OsStr::new(name),
+                0o600,
+                libc::O_WRONLY as u32,
+            )
+            .unwrap();
+        let path = Path::new("/").join(name);
+        m.fs.write(req(), &path, created.fh, 0, b"abc".to_vec(), 0)
+            .unwrap();
+        m.fs.release(req(), &path, created.fh, 0, 0, true).unwrap();
+    };
+
+    // release() returns before the upload, which fsync() waits for
+    m.mock.hold_uploads(true);
+    create("a.txt");
+    assert_eq!(m.mock.find("0", "a.txt"), None);
+    assert_eq!(m.readdir("/"), vec!["?Backup", "?Recycle", "a.txt"]);
+    uploading();
+    let path = Path::new("/a.txt");
+    let (fh, _) = m.fs.open(req(), path, libc::O_RDONLY as u32).unwrap();
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            std::thread::sleep(Duration::from_millis(50));
+            m.mock.hold_uploads(false);
+        });
+        m.fs.fsync(req(), path, fh, false).unwrap();
+    });
+    let id = m.mock.find("0", "a.txt").unwrap();
+    assert_eq!(m.mock.content(&id).unwrap(), b"abc");
+    // a handle opened meanwhile goes on with the uploaded file
+    assert_eq!(m.fs.read_data(fh, 0, 100).unwrap(), b"abc");
+    assert_eq!(m.fs.getattr(req(), path, Some(fh)).unwrap().1.size, 3);
+    m.fs.release(req(), path, fh, 0, 0, true).unwrap();
+    assert_eq!(m.mock.downloaded(), 0);
+
+    // a failed upload is returned by fsync(), and tried again on release
+    m.mock.hold_uploads(true);
+    create("b.txt");
+    uploading();
+    let path = Path::new("/b.txt");
+    let (fh, _) = m.fs.open(req(), path, libc::O_RDONLY as u32).unwrap();
+    // the held chunk, then the resumed one and the new upload of fsync()
+    m.mock.fail_next(3, 507);
+    m.mock.hold_uploads(false);
+    assert_eq!(m.fs.fsync(req(), path, fh, false), Err(libc::ENOSPC));
+    assert_eq!(m.mock.find("0", "b.txt"), None);
+    assert_eq!(
+        m.readdir("/"),
+        vec!["?Backup", "?Recycle", "a.txt", "b.txt"]
+    );
+    m.fs.release(req(), path, fh, 0, 0, true).unwrap();
+    m.fs.wait_uploads();
+    assert!(m.mock.find("0", "b.txt").is_some());
+    // which is not a failure anymore
+    let status = m.xattr("/", "user.recfs.uploads");
+    assert!(status.contains("failed: 0\n"), "{}", status);
+
+    // written again while it is being uploaded: uploaded again
+    m.mock.hold_uploads(true);
+    create("d.txt");
+    uploading();
+    let path = Path::new("/d.txt");
+    let (fh, _) = m.fs.open(req(), path, libc::O_WRONLY as u32).unwrap();
+    m.fs.write(req(), path, fh, 0, b"abcdef".to_vec(), 0)
+        .unwrap();
+    m.fs.release(req(), path, fh, 0, 0, true).unwrap();
+    m.mock.hold_uploads(false);
+    m.fs.wait_uploads();
+    let id = m.mock.find("0", "d.txt").unwrap();
+    assert_eq!(m.mock.content(&id).unwrap(), b"abcdef");
+    // the stale copy is recycled
+    assert_eq!(m.readdir("/?Recycle"), vec!["d.txt"]);
+
+    // fsync() through the handle being written uploads it, what follows is written back
+    let created =
+        m.fs.create(
+            req(),
+            Path::new("/"),
+            OsStr::new("e.txt"),
+            0o600,
+            libc::O_WRONLY as u32,
+        )
+        .unwrap();
+    let path = Path::new("/e.txt");
+    m.fs.write(req(), path, created.fh, 0, b"abc".to_vec(), 0)
+        .unwrap();
+    m.fs.fsync(req(), path, created.fh, false).unwrap();
+    let id = m.mock.find("0", "e.txt").unwrap();
+    assert_eq!(m.mock.content(&id).unwrap(), b"abc");
+    m.fs.write(req(), path, created.fh, 3, b"def".to_vec(), 0)
+        .unwrap();
+    m.fs.release(req(), path, created.fh, 0, 0, true).unwrap();
+    let id = m.mock.find("0", "e.txt").unwrap();
+    assert_eq!(m.mock.content(&id).unwrap(), b"abcdef");
+
+    // uploaded, but not listed by rec: only the listing is tried again
+    m.mock.hold_uploads(true);
+    create("f.txt");
+    uploading();
+    m.mock.fail_lists(3);
+    m.mock.hold_uploads(false);
+    m.fs.wait_uploads();
+    let status = m.xattr("/", "user.recfs.uploads");
+    assert!(status.contains("failed: 1\nf.txt: "), "{}", status);
+    let id = m.mock.find("0", "f.txt").unwrap();
+    let parts = m.mock.parts();
+    let path = Path::new("/f.txt");
+    let (fh, _) = m.fs.open(req(), path, libc::O_RDONLY as u32).unwrap();
+    m.fs.fsync(req(), path, fh, false).unwrap();
+    m.fs.release(req(), path, fh, 0, 0, true).unwrap();
+    assert_eq!(m.mock.find("0", "f.txt").unwrap(), id);
+    assert_eq!(m.mock.find("0", "f(1).txt"), None);
+    assert_eq!(m.mock.parts(), parts);
+
+    // queued files are uploaded before unmounting
+    m.mock.hold_uploads(true);
+    create("c.txt");
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            std::thread::sleep(Duration::from_millis(50));
+            m.mock.hold_uploads(false);
+        });
+        m.fs.destroy();
+    });
+    assert!(m.mock.find("0", "c.txt").is_some());
+    let status = m.xattr("/", "user.recfs.uploads");
+    assert_eq!(status, "queued: 0\nuploading: 0\nuploaded: 6\nfailed: 0\n");
+}
+
+#[test]
+fn test_handles() {
+    let m = Mounted::new();
+    let old = m.mock.add_file("0", "a.txt", b"hello world");
+
+    // chunks written through one handle must not truncate each other
+    let created =
+        m.fs.create(
+            req(),
+            Path::new("/"),
+            OsStr::new("b.txt"),
+            0o600,
+            libc::O_WRONLY as u32,
+        )
+        .unwrap();
+    let path = Path::new("/b.txt");
+    for (offset, chunk) in [(0, &b"hello "[..]), (6, &b"world"[..])] {
+        m.fs.write(req(), path, created.fh, offset, chunk.to_vec(), 0)
+            .unwrap();
+    }
+    assert_eq!(m.fs.read_data(created.fh, 0, 5), Err(libc::EBADF));
+    m.fs.release(req(), path, created.fh, 0, 0, true).unwrap();
+    m.fs.wait_uploads();
+    let b = m.mock.find("0", "b.txt").unwrap();
+    assert_eq!(m.mock.content(&b).unwrap(), b"hello world");
+
+    // every open() gets its own handle, with its own access mode
+    let path = Path::new("/a.txt");
+    let (ro, _) = m.fs.open(req(), path, libc::O_RDONLY as u32).unwrap();
+    let (rw, _) = m.fs.open(req(), path, libc::O_RDWR as u32).unwrap();
+    assert_ne!(ro, rw);
+    assert_eq!(
+        m.fs.write(req(), path, ro, 0, b"x".to_vec(), 0),
+        Err(libc::EBADF)
+    );
+    assert_eq!(m.fs.read_data(ro, 6, 100).unwrap(), b"world");
+    assert_eq!(m.fs.read_data(rw, 0, 5).unwrap(), b"hello");
+    assert_eq!(m.fs.read_data(rw, 20, 5).unwrap(), b"");
+    m.fs.release(req(), path, ro, 0, 0, true).unwrap();
+    assert_eq!(m.fs.release(req(), path, ro, 0, 0, true), Err(libc::EBADF));
+    // nothing was written: no write back
+    m.fs.release(req(), path, rw, 0, 0, true).unwrap();
+    assert_eq!(m.mock.find("0", "a.txt"), Some(old));
+
+    let (dir, _) = m.fs.opendir(req(), Path::new("/"), 0).unwrap();
+    assert_eq!(m.fs.readdir(req(), Path::new("/"), dir).unwrap().len(), 4);
+    m.fs.releasedir(req(), Path::new("/"), dir, 0).unwrap();
+    assert!(m.fs.readdir(req(), Path::new("/"), dir).is_err());
+}
+
+#[test]
+fn test_pending() {
+    let m = Mounted::new();
+    let path = Path::new("/new.txt");
+    let created =
+        m.fs.create(
+            req(),
+            Path::new("/"),
+            OsStr::new("new.txt"),
+            0o600,
+            libc::O_WRONLY as u32,
+        )
+        .unwrap();
+    m.fs.write(req(), path, created.fh, 0, b"abc".to_vec(), 0)
+        .unwrap();
+
+    // visible while it is being written
+    let (_, attr) = m.fs.getattr(req(), path, None).unwrap();
+    assert_eq!(attr.size, 3);
+    assert_eq!(attr.kind, FileType::RegularFile);
+    assert_eq!(m.readdir("/"), vec!["?Backup", "?Recycle", "new.txt"]);
+    let (fh, _) = m.fs.open(req(), path, libc::O_RDONLY as u32).unwrap();
+    assert_eq!(m.fs.read_data(fh, 0, 100).unwrap(), b"abc");
+
+    // uploaded when the last handle is closed
+    m.fs.release(req(), path, created.fh, 0, 0, true).unwrap();
+    assert_eq!(m.mock.find("0", "new.txt"), None);
+    m.fs.release(req(), path, fh, 0, 0, true).unwrap();
+    m.fs.wait_uploads();
+    let id = m.mock.find("0", "new.txt").unwrap();
+    assert_eq!(m.mock.content(&id).unwrap(), b"abc");
+    assert_eq!(m.readdir("/"), vec!["?Backup", "?Recycle", "new.txt"]);
+    assert_eq!(m.fs.getattr(req(), path, None).unwrap().1.size, 3);
+
+    // unlinked before upload: never reaches rec
+    let path = Path::new("/gone.txt");
+    let created =
+        m.fs.create(
+            req(),
+            Path::new("/"),
+            OsStr::new("gone.txt"),
+            0o600,
+            libc::O_WRONLY as u32,
+        )
+        .unwrap();
+    m.fs.write(req(), path, created.fh, 0, b"abc".to_vec(), 0)
+        .unwrap();
+    m.fs.unlink(req(), Path::new("/"), OsStr::new("gone.txt"))
+        .unwrap();
+    assert_eq!(m.fs.getattr(req(), path, None).err(), Some(libc::ENOENT));
+    assert_eq!(m.readdir("/"), vec!["?Backup", "?Recycle", "new.txt"]);
+    // but still usable through its handle, as a temporary file
+    m.fs.write(req(), path, created.fh, 3, b"def".to_vec(), 0)
+        .unwrap();
+    let (_, attr) = m.fs.getattr(req(), path, Some(created.fh)).unwrap();
+    assert_eq!(attr.size, 6);
+    m.fs.release(req(), path, created.fh, 0, 0, true).unwrap();
+    m.fs.wait_uploads();
+    assert_eq!(m.mock.find("0", "gone.txt"), None);
+    assert_eq!(
+        m.fs.getattr(req(), path, Some(created.fh)).err(),
+        Some(libc::EBADF)
+    );
+    let left: Vec<_> = std::fs::read_dir(m.dir.join("cache"))
+        .unwrap()
+        .flatten()
+        .filter(|e| e.file_name().to_string_lossy().starts_with("write-"))
+        .collect();
+    assert!(left.is_empty());
+}
+
+#[test]
+fn test_ranged_read() {
+    let m = Mounted::with_cache(CacheConfig {
+        block_size: 8,
+        readahead: 0,
+        ..Default::default()
+    });
+    let content: Vec<u8> = (0..40).collect();
+    m.mock.add_file("0", "a.bin", &content);
+
+    let path = Path::new("/a.bin");
+    let (fh, _) = m.fs.open(req(), path, libc::O_RDONLY as u32).unwrap();
+    assert_eq!(m.fs.read_data(fh, 10, 4).unwrap(), &content[10..14]);
+    assert_eq!(m.mock.downloaded(), 8);
+    assert_eq!(m.fs.read_data(fh, 12, 10).unwrap(), &content[12..22]);
+    assert_eq!(m.mock.downloaded(), 16);
+    assert_eq!(m.fs.read_data(fh, 0, 100).unwrap(), content);
+    assert_eq!(m.mock.downloaded(), 40);
+    m.fs.release(req(), path, fh, 0, 0, true).unwrap();
+
+    // complete now: served from the local copy
+    assert_eq!(m.read("/a.bin"), content);
+    assert_eq!(m.mock.downloaded(), 40);
+}
+
+#[test]
+fn test_readahead() {
+    let m = Mounted::with_cache(CacheConfig {
+        block_size: 8,
+        readahead: 16,
+        ..Default::default()
+    });
+    let content: Vec<u8> = (0..64).collect();
+    m.mock.add_file("0", "a.bin", &content);
+
+    let path = Path::new("/a.bin");
+    let (fh, _) = m.fs.open(req(), path, libc::O_RDONLY as u32).unwrap();
+    assert_eq!(m.fs.read_data(fh, 0, 4).unwrap(), &content[0..4]);
+    // the next two blocks arrive in the background
+    let start = std::time::Instant::now();
+    while m.mock.downloaded() < 24 && start.elapsed() < Duration::from_secs(5) {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(m.mock.downloaded(), 24);
+    // a random read does not trigger readahead
+    assert_eq!(m.fs.read_data(fh, 40, 4).unwrap(), &content[40..44]);
+    assert_eq!(m.fs.read_data(fh, 8, 16).unwrap(), &content[8..24]);
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(m.mock.downloaded(), 32);
+    m.fs.release(req(), path, fh, 0, 0, true).unwrap();
+}
+
+#[test]
+fn test_cache_budget() {
+    let m = Mounted::with_cache(CacheConfig {
+        max_size: 20,
+        ..Default::default()
+    });
+    let a = m.mock.add_file("0", "a.txt", b"0123456789");
+    let b = m.mock.add_file("0", "b.txt", b"0123456789");
+    let c = m.mock.add_file("0", "c.txt", b"0123456789");
+    let cached = |id: &str| m.dir.join("cache").join(id).exists();
+
+    // an open file is never evicted
+    let path = Path::new("/a.txt");
+    let (fh, _) = m.fs.open(req(), path, libc::O_RDONLY as u32).unwrap();
+    m.fs.read_data(fh, 0, 10).unwrap();
+    m.read("/b.txt");
+    m.read("/c.txt");
+    assert!(cached(&a) && !cached(&b) && cached(&c));
+    m.fs.release(req(), path, fh, 0, 0, true).unwrap();
+    m.read("/b.txt");
+    assert!(!cached(&a) && cached(&b) && cached(&c));
+
+    // modified files are kept until they are uploaded
+    m.read("/c.txt");
+    let path = Path::new("/b.txt");
+    let (fh, _) = m.fs.open(req(), path, libc::O_RDWR as u32).unwrap();
+    m.fs.write(req(), path, fh, 0, b"x".to_vec(), 0).unwrap();
+    m.read("/a.txt");
+    assert!(cached(&b));
+    assert_eq!(m.mock.downloaded(), 50);
+
+    // on unmount, only files which are not uploaded are kept
+    let created =
+        m.fs.create(
+            req(),
+            Path::new("/"),
+            OsStr::new("new.txt"),
+            0o600,
+            libc::O_WRONLY as u32,
+        )
+        .unwrap();
+    m.fs.write(
+        req(),
+        Path::new("/new.txt"),
+        created.fh,
+        0,
+        b"new".to_vec(),
+        0,
+    )
+    .unwrap();
+    m.fs.destroy();
+    // in lost+found next to the temporary cache folder, which is gone
+    assert!(!m.dir.join("cache").exists());
+    let mut left: Vec<String> = std::fs::read_dir(m.dir.join("lost+found"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    left.sort();
+    assert_eq!(left, vec![b, "write-0".to_owned()]);
+}
+
+#[test]
+fn test_persistent_cache() {
+    let config = CacheConfig {
+        persistent: true,
+        ..Default::default()
+    };
+    let mut m = Mounted::with_cache(config.clone());
+    let a = m.mock.add_file("0", "a.txt", b"hello");
+    m.mock.add_file("0", "b.txt", b"world");
+    assert_eq!(m.read("/a.txt"), b"hello");
+    assert_eq!(m.read("/b.txt"), b"world");
+    assert_eq!(m.mock.downloaded(), 10);
+
+    // downloaded files are reused by the next mount
+    m.remount(config.clone());
+    assert_eq!(m.read("/a.txt"), b"hello");
+    assert_eq!(m.mock.downloaded(), 10);
+
+    // unless they have changed on rec, once the restored listings are revalidated
+    m.mock.set_content(&a, b"hello again");
+    m.remount(config.clone());
+    m.fs.poll();
+    assert_eq!(m.read("/a.txt"), b"hello again");
+    assert_eq!(m.read("/b.txt"), b"world");
+    assert_eq!(m.mock.downloaded(), 21);
+
+    // files which were not uploaded are moved away
+    let path = Path::new("/b.txt");
+    let (fh, _) = m.fs.open(req(), path, libc::O_RDWR as u32).unwrap();
+    m.fs.write(req(), path, fh, 0, b"W".to_vec(), 0).unwrap();
+    m.remount(config);
+    assert_eq!(m.read("/b.txt"), b"world");
+    assert_eq!(m.mock.downloaded(), 26);
+    let lost = m.dir.join("cache").join("lost+found");
+    assert_eq!(lost.read_dir().unwrap().count(), 1);
+}
+
+#[test]
+fn test_upload_journal() {
+    let config = CacheConfig {
+        persistent: true,
+        ..Default::default()
+    };
+    let mut m = Mounted::with_cache(config.clone());
+    let create = |fs: &RecFs, name: &str, data: &[u8]| {
+        let created = fs
+            .create(
+                req(),
+                Path::new("/"),
+                OsStr::new(name),
+                0o600,
+                libc::O_WRONLY as u32,
+            )
+            .unwrap();
+        let path = Path::new("/").join(name);
+        fs.write(req(), &path, created.fh, 0, data.to_vec(), 0)
+            .unwrap();
+        (path, created.fh)
+    };
+
+    // crash while a.txt is being uploaded and b.txt is still open
+    m.mock.hold_uploads(true);
+    let (path, fh) = create(&m.fs, "a.txt", b"aaa");
+    m.fs.release(req(), &path, fh, 0, 0, true).unwrap();
+    create(&m.fs, "b.txt", b"bbb");
+    while m.mock.uploading() == 0 {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let crashed = m.dir.join("crashed");
+    std::fs::create_dir_all(crashed.join("cache").join("lost+found")).unwrap();
+    for entry in std::fs::read_dir(m.dir.join("cache")).unwrap() {
+        let entry = entry.unwrap();
+        std::fs::copy(entry.path(), crashed.join("cache").join(entry.file_name())).unwrap();
+    }
+    m.mock.hold_uploads(false);
+    m.fs.wait_uploads();
+    m.fs.destroy();
+
+    // files not in the journal are kept in lost+found, next to older ones
+    let lost = crashed.join("cache").join("lost+found");
+    std::fs::write(crashed.join("cache").join("write-7"), b"old").unwrap();
+    std::fs::write(lost.join("write-7"), b"older").unwrap();
+    m.fs = mount(&m.mock, &crashed, config, m.ttl.clone());
+    assert_eq!(std::fs::read(lost.join("write-7.1")).unwrap(), b"old");
+    let journal = crashed.join("cache").join("uploads.json");
+    let pending = || -> Vec<String> {
+        let data = std::fs::read(&journal).unwrap();
+        let entries: Vec<serde_json::Value> = serde_json::from_slice(&data).unwrap();
+        entries
+            .iter()
+            .map(|e| e["name"].as_str().unwrap().to_owned())
+            .collect()
+    };
+    assert_eq!(pending(), vec!["a.txt", "b.txt"]);
+
+    // what reached rec is not uploaded again
+    m.fs.recover_uploads();
+    m.fs.wait_uploads();
+    assert_eq!(
+        m.readdir("/"),
+        vec!["?Backup", "?Recycle", "a.txt", "b.txt"]
+    );
+    assert_eq!(m.read("/b.txt"), b"bbb");
+    assert!(pending().is_empty());
+    assert!(!crashed.join("cache").join("write-1").exists());
+
+    // new files do not take the place of the ones left
+    create(&m.fs, "c.txt", b"ccc");
+    assert!(crashed.join("cache").join("write-2").exists());
+}
+
+#[test]
+fn test_resume_upload() {
+    let config = CacheConfig {
+        persistent: true,
+        ..Default::default()
+    };
+    let mut m = Mounted::with_cache(config.clone());
+    m.mock.set_chunk_size(1000);
+    let data: Vec<u8> = (0..4500u32).map(|i| i as u8).collect();
+
+    // chunks uploaded before a failure are kept in the journal
+    m.mock.fail_parts_after(Some(3));
+    m.write_new("/", "big.bin", &data).unwrap();
+    assert_eq!(m.mock.find("0", "big.bin"), None);
+    assert_eq!(m.mock.parts(), 3);
+
+    // and not uploaded again by the next mount
+    m.mock.fail_parts_after(None);
+    m.remount(config);
+    m.fs.recover_uploads();
+    m.fs.wait_uploads();
+    assert_eq!(m.mock.parts(), 5);
+    let id = m.mock.find("0", "big.bin").unwrap();
+    assert_eq!(m.mock.content(&id).unwrap(), data);
+}
+
+#[test]
+fn test_resume_download() {
+    let config = CacheConfig {
+        block_size: 1000,
+        readahead: 0,
+        persistent: true,
+        ..Default::default()
+    };
+    let mut m = Mounted::with_cache(config.clone());
+    let data: Vec<u8> = (0..5000u32).map(|i| (i * 3) as u8).collect();
+    let a = m.mock.add_file("0", "a.bin", &data);
+
+    // a download cut short goes on from where it stopped
+    let path = Path::new("/a.bin");
+    let (fh, _) = m.fs.open(req(), path, libc::O_RDONLY as u32).unwrap();
+    m.mock.cut_downloads(2, 300);
+    assert_eq!(m.fs.read_data(fh, 0, 2000).unwrap(), &data[..2000]);
+    assert_eq!(m.mock.downloaded(), 2000);
+    m.fs.release(req(), path, fh, 0, 0, true).unwrap();
+
+    // and an unfinished one is resumed by the next mount
+    let blocks = m.dir.join("cache").join(format!("{}.blocks", a));
+    assert!(blocks.exists());
+    m.remount(config);
+    assert_eq!(m.read("/a.bin"), data);
+    assert_eq!(m.mock.downloaded(), 5000);
+    assert!(!blocks.exists());
+    assert!(m.dir.join("cache").join(&a).exists());
+
+    // a server ignoring Range sends the file from its start, which may break off anywhere
+    m.mock.add_file("0", "b.bin", &data);
+    m.mock.ignore_ranges(true);
+    let path = Path::new("/b.bin");
+    let (fh, _) = m.fs.open(req(), path, libc::O_RDONLY as u32).unwrap();
+    // past the blocks asked for, which need nothing more
+    let downloaded = m.mock.downloaded();
+    m.mock.cut_downloads(1, 2500);
+    assert_eq!(m.fs.read_data(fh, 1000, 1000).unwrap(), &data[1000..2000]);
+    assert_eq!(m.mock.downloaded(), downloaded + 2500);
+    // and before them
+    m.mock.cut_downloads(1, 2500);
+    assert_eq!(m.fs.read_data(fh, 3000, 1000).unwrap(), &data[3000..4000]);
+    assert_eq!(m.fs.read_data(fh, 0, 5000).unwrap(), data);
+    m.fs.release(req(), path, fh, 0, 0, true).unwrap();
+    m.mock.ignore_ranges(false);
+
+    // a partial copy changed on rec is kept by the handle reading it until it is closed
+    let c = m.mock.add_file("0", "c.bin", &data);
+    let path = Path::new("/c.bin");
+    let (fh, _) = m.fs.open(req(), path, libc::O_RDONLY as u32).unwrap();
+    assert_eq!(m.fs.read_data(fh, 0, 1000).unwrap(), &data[..1000]);
+    let mut changed = data.clone();
+    changed[..1000].fill(b'x');
+    m.mock.set_content(&c, &changed);
+    m.fs.poll();
+    assert_eq!(m.fs.read_data(fh, 1000, 1000).unwrap(), &data[1000..2000]);
+    m.fs.release(req(), path, fh, 0, 0, true).unwrap();
+    assert_eq!(m.read("/c.bin"), changed);
+}
+
+#[test]
+fn test_split_download() {
+    let m = Mounted::with_cache(CacheConfig {
+        block_size: 1000,
+        readahead: 0,
+        connections: 3,
+        min_split: 2000,
+        ..Default::default()
+    });
+    let data: Vec<u8> = (0..10_500u32).map(|i| (i * 7) as u8).collect();
+    m.mock.add_file("0", "big.bin", &data);
+    m.mock.add_file("0", "small.bin", &data[..3000]);
+
+    // 11 blocks over 3 connections
+    assert_eq!(m.read("/big.bin"), data);
+    assert_eq!(m.mock.ranges(), 3);
+    assert_eq!(m.mock.downloaded(), data.len() as u64);
+
+    // too small to be split
+    assert_eq!(m.read("/small.bin"), &data[..3000]);
+    assert_eq!(m.mock.ranges(), 4);
+}
+
+#[test]
+fn test_download_urls() {
+    let m = Mounted::with_cache(CacheConfig {
+        block_size: 2,
+        readahead: 0,
+        ..Default::default()
+    });
+    let p = m.mock.add_folder("0", "p");
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        m.mock.add_file(&p, name, b"abc");
+    }
+    m.mock.add_file("0", "y.txt", b"yyyyyy");
+
+    // the files of a pinned folder get their urls in one request
+    m.fs.setxattr(
+        req(),
+        Path::new("/p"),
+        OsStr::new("user.recfs.pin"),
+        b"1",
+        0,
+        0,
+    )
+    .unwrap();
+    for _ in 0..100 {
+        if m.xattr("/p", "user.recfs.cached") == "1" {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(m.xattr("/p", "user.recfs.cached"), "1");
+    assert_eq!(m.mock.url_requests(), 1);
+
+    // a url is reused by later reads
+    let path = Path::new("/y.txt");
+    let (fh, _) = m.fs.open(req(), path, libc::O_RDONLY as u32).unwrap();
+    assert_eq!(m.fs.read_data(fh, 0, 2).unwrap(), b"yy");
+    assert_eq!(m.fs.read_data(fh, 2, 2).unwrap(), b"yy");
+    assert_eq!(m.mock.url_requests(), 2);
+
+    // until it expires
+    m.mock.expire_urls();
+    assert_eq!(m.fs.read_data(fh, 4, 2).unwrap(), b"yy");
+    assert_eq!(m.mock.url_requests(), 3);
+    m.fs.release(req(), path, fh, 0, 0, true).unwrap();
+}
+
+#[test]
+fn test_prefetch() {
+    let m = Mounted::with_cache(CacheConfig {
+        prefetch_files: 3,
+        prefetch_size: 25,
+        ..Default::default()
+    });
+    let d = m.mock.add_folder("0", "d");
+    for i in 0..6 {
+        m.mock.add_file(&d, &format!("f{}.txt", i), b"0123456789");
+    }
+    // in the order rec lists them, as cp -r reads them
+    let (fh, _) = m.fs.opendir(req(), Path::new("/d"), 0).unwrap();
+    let names: Vec<String> =
+        m.fs.readdir(req(), Path::new("/d"), fh)
+            .unwrap()
+            .into_iter()
+            .map(|e| format!("/d/{}", e.name.to_string_lossy()))
+            .collect();
+    m.fs.releasedir(req(), Path::new("/d"), fh, 0).unwrap();
+    let cached = |i: usize| m.xattr(&names[i], "user.recfs.cached") == "1";
+
+    // one file read is not a traversal yet
+    m.read(&names[0]);
+    m.fs.wait_prefetch();
+    assert!(!cached(1));
+
+    // the next one is: the files after it are downloaded, within 25 bytes
+    m.read(&names[1]);
+    m.fs.wait_prefetch();
+    assert!(cached(2) && cached(3) && !cached(4));
+    assert_eq!(m.mock.downloaded(), 40);
+
+    // and the window moves on with the traversal
+    assert_eq!(m.read(&names[2]), b"0123456789");
+    m.fs.wait_prefetch();
+    assert!(cached(4) && !cached(5));
+    assert_eq!(m.mock.downloaded(), 50);
+}
+
+#[test]
+fn test_cache_lock() {
+    let config = CacheConfig {
+        persistent: true,
+        ..Default::default()
+    };
+    let mut m = Mounted::with_cache(config.clone());
+
+    // a second mount must not share the folder
+    let err = Cache::new(m.dir.join("cache"), config.clone())
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("another recfs mount"));
+
+    // which is free again after unmounting
+    m.remount(config);
+    assert!(m.dir.join("cache").join("lock").exists());
+    assert!(!m.dir.join("cache").join("lost+found").exists());
+}
+
+#[test]
+fn test_listing_ttl() {
+    let ttl = Ttl {
+        listing: Duration::from_millis(300),
+        negative: Duration::from_millis(300),
+    };
+    let m = Mounted::with_options(CacheConfig::default(), ttl);
+    let d = m.mock.add_folder("0", "d");
+    m.mock.add_file(&d, "a.txt", b"a");
+    assert_eq!(m.readdir("/d"), vec!["a.txt"]);
+
+    // a missing name is looked up on rec once, then remembered for a while
+    assert_eq!(
+        m.fs.getattr(req(), Path::new("/d/b.txt"), None).err(),
+        Some(libc::ENOENT)
+    );
+    m.mock.add_file(&d, "b.txt", b"b");
+    assert_eq!(
+        m.fs.getattr(req(), Path::new("/d/b.txt"), None).err(),
+        Some(libc::ENOENT)
+    );
+    assert_eq!(m.readdir("/d"), vec!["a.txt"]);
+
+    // expired listings are fetched again
+    std::thread::sleep(Duration::from_millis(350));
+    assert!(m.fs.getattr(req(), Path::new("/d/b.txt"), None).is_ok());
+    m.mock.add_file(&d, "c.txt", b"c");
+    assert_eq!(m.readdir("/d"), vec!["a.txt", "b.txt"]);
+    std::thread::sleep(Duration::from_millis(350));
+    assert_eq!(m.readdir("/d"), vec!["a.txt", "b.txt", "c.txt"]);
+}
+
+#[test]
+fn test_poll() {
+    let m = Mounted::new();
+    let d = m.mock.add_folder("0", "d");
+    let e = m.mock.add_folder(&d, "e");
+    let a = m.mock.add_file(&d, "a.txt", b"hello");
+    let b = m.mock.add_file(&d, "b.txt", b"b");
+    assert_eq!(m.readdir("/d"), vec!["a.txt", "b.txt", "e"]);
+    assert_eq!(m.readdir("/d/e"), Vec::<String>::new());
+    assert_eq!(m.read("/d/a.txt"), b"hello");
+
+    // changes made elsewhere are picked up without waiting for the listing TTL
+    m.mock.set_content(&a, b"hello again");
+    m.mock.recycle(&b);
+    m.mock.recycle(&e);
+    m.mock.add_file(&d, "c.txt", b"c");
+    let summary = m.fs.poll();
+    assert_eq!(
+        (summary.added, summary.removed, summary.modified),
+        (1, 2, 1)
+    );
+    assert_eq!(m.readdir("/d"), vec!["a.txt", "c.txt"]);
+    assert_eq!(m.read("/d/a.txt"), b"hello again");
+    assert_eq!(m.mock.downloaded(), 16);
+
+    // removed folders are not polled anymore
+    let summary = m.fs.poll();
+    assert_eq!(summary.dirs, 2);
+    assert_eq!(
+        (summary.added, summary.removed, summary.modified),
+        (0, 0, 0)
+    );
+}
+
+#[test]
+fn test_warm_start() {
+    let config = CacheConfig {
+        persistent: true,
+        ..Default::default()
+    };
+    let mut m = Mounted::with_options(config.clone(), Ttl::default());
+    let d = m.mock.add_folder("0", "d");
+    m.mock.add_folder(&d, "e");
+    m.mock.add_file(&d, "a.txt", b"a");
+    assert_eq!(m.readdir("/d"), vec!["a.txt", "e"]);
+    assert_eq!(m.readdir("/d/e"), Vec::<String>::new());
+
+    // fresh listings are used by the next mount without listing again
+    m.remount(config.clone());
+    let listed = m.mock.listed();
+    assert_eq!(m.readdir("/d"), vec!["a.txt", "e"]);
+    assert_eq!(m.readdir("/d/e"), Vec::<String>::new());
+    assert_eq!(m.mock.listed(), listed);
+
+    // expired ones are used too, and revalidated in background
+    m.mock.add_file(&d, "b.txt", b"b");
+    m.ttl.listing = Duration::ZERO;
+    m.remount(config);
+    assert_eq!(m.readdir("/d"), vec!["a.txt", "e"]);
+    for _ in 0..100 {
+        if m.readdir("/d").len() == 3 {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(m.readdir("/d"), vec!["a.txt", "b.txt", "e"]);
+}
+
+#[test]
+fn test_offline() {
+    let config = CacheConfig {
+        persistent: true,
+        ..Default::default()
+    };
+    let mut m = Mounted::with_cache(config.clone());
+    let d = m.mock.add_folder("0", "d");
+    m.mock.add_folder(&d, "e");
+    m.mock.add_file(&d, "a.txt", b"cached");
+    m.mock.add_file(&d, "b.txt", b"not cached");
+    assert_eq!(m.readdir("/d"), vec!["a.txt", "b.txt", "e"]);
+    assert_eq!(m.read("/d/a.txt"), b"cached");
+
+    // folders and files seen before are served without any request
+    m.ttl.listing = Duration::ZERO;
+    m.remount(config.clone());
+    m.fs.set_offline();
+    let (listed, downloaded) = (m.mock.listed(), m.mock.downloaded());
+    assert_eq!(m.readdir("/d"), vec!["a.txt", "b.txt", "e"]);
+    assert_eq!(m.read("/d/a.txt"), b"cached");
+    let path = Path::new("/d/b.txt");
+    let (fh, _) = m.fs.open(req(), path, libc::O_RDONLY as u32).unwrap();
+    assert_eq!(m.fs.read_data(fh, 0, 10).err(), Some(libc::EIO));
+    let (fh, _) = m.fs.opendir(req(), Path::new("/d/e"), 0).unwrap();
+    assert_eq!(
+        m.fs.readdir(req(), Path::new("/d/e"), fh).err(),
+        Some(libc::EIO)
+    );
+    assert_eq!(
+        m.fs.getattr(req(), Path::new("/d/c.txt"), None).err(),
+        Some(libc::ENOENT)
+    );
+    assert_eq!((m.mock.listed(), m.mock.downloaded()), (listed, downloaded));
+
+    // and nothing can be modified
+    let mkdir = m.fs.mkdir(req(), Path::new("/d"), OsStr::new("f"), 0o700);
+    assert_eq!(mkdir.err(), Some(libc::EROFS));
+    let create = m.fs.create(
+        req(),
+        Path::new("/d"),
+        OsStr::new("c.txt"),
+        0o600,
+        libc::O_WRONLY as u32,
+    );
+    assert_eq!(create.err(), Some(libc::EROFS));
+    let open = m.fs.open(req(), Path::new("/d/a.txt"), libc::O_RDWR as u32);
+    assert_eq!(open.err(), Some(libc::EROFS));
+    let unlink = m.fs.unlink(req(), Path::new("/d"), OsStr::new("a.txt"));
+    assert_eq!(unlink.err(), Some(libc::EROFS));
+
+    // rec being unreachable switches to offline mode too
+    m.fs.destroy();
+    let unused = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let api_url = format!("http://{}/api/v2/", unused.local_addr().unwrap());
+    drop(unused);
+    m.fs = RecFs::with_client(
+        client(&m.mock, api_url),
+        Cache::new(m.dir.join("cache"), config).unwrap(),
+        m.ttl.clone(),
+        2,
+    );
+    assert_eq!(
+        m.fs.getattr(req(), Path::new("/d/c.txt"), None).err(),
+        Some(libc::ENOENT)
+    );
+    assert_eq!(m.read("/d/a.txt"), b"cached");
+    let mkdir = m.fs.mkdir(req(), Path::new("/d"), OsStr::new("f"), 0o700);
+    assert_eq!(mkdir.err(), Some(libc::EROFS));
+}
+
+#[test]
+fn test_pin() {
+    let m = Mounted::with_cache(CacheConfig {
+        max_size: 10,
+        ..Default::default()
+    });
+    let p = m.mock.add_folder("0", "p");
+    let q = m.mock.add_folder(&p, "q");
+    let a = m.mock.add_file(&p, "a.txt", b"aaaaa");
+    m.mock.add_file(&q, "b.txt", b"bbbbb");
+    m.mock.add_file("0", "x.txt", b"xxxxxxxxxx");
+    assert_eq!(m.xattr("/p", "user.recfs.pin"), "0");
+    assert_eq!(m.xattr("/p", "user.recfs.cached"), "0");
+
+    // pinning a folder downloads everything in it
+    m.fs.setxattr(
+        req(),
+        Path::new("/p"),
+        OsStr::new("user.recfs.pin"),
+        b"1",
+        0,
+        0,
+    )
+    .unwrap();
+    for _ in 0..100 {
+        if m.xattr("/p", "user.recfs.cached") == "1" {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(m.xattr("/p", "user.recfs.cached"), "1");
+    assert_eq!(m.xattr("/p/q/b.txt", "user.recfs.pin"), "1");
+    assert_eq!(m.mock.downloaded(), 10);
+
+    // which is never evicted
+    assert_eq!(m.read("/x.txt"), b"xxxxxxxxxx");
+    assert_eq!(m.xattr("/x.txt", "user.recfs.cached"), "0");
+    assert_eq!(m.xattr("/p", "user.recfs.cached"), "1");
+
+    // and downloaded again when it changes
+    m.mock.set_content(&a, b"AAAAA");
+    m.fs.poll();
+    assert_eq!(m.xattr("/p/a.txt", "user.recfs.cached"), "1");
+    assert_eq!(m.mock.downloaded(), 25);
+    assert_eq!(m.read("/p/a.txt"), b"AAAAA");
+    assert_eq!(m.mock.downloaded(), 25);
+
+    let names = match m.fs.listxattr(req(), Path::new("/p"), 256) {
+        Ok(Xattr::Data(data)) => data,
+        res => panic!("listxattr: {:?}", res.err()),
+    };
+    assert_eq!(
+        names,
+        b"user.recfs.pin\0user.recfs.cached\0user.recfs.fid\0user.recfs.disk\0"
+    );
+    m.fs.removexattr(req(), Path::new("/p"), OsStr::new("user.recfs.pin"))
+        .unwrap();
+    assert_eq!(m.xattr("/p/a.txt", "user.recfs.pin"), "0");
+    let other =
+        m.fs.getxattr(req(), Path::new("/p"), OsStr::new("user.other"), 0);
+    assert_eq!(other.err(), Some(libc::ENODATA));
+}
+
+#[test]
+fn test_xattr_metadata() {
+    let m = Mounted::new();
+    let a = m.mock.add_file("0", "a.txt", b"hello");
+    let b = m.mock.add_file("0", "b.txt", b"b");
+    m.mock.recycle(&b);
+
+    assert_eq!(m.xattr("/a.txt", "user.recfs.fid"), a);
+    assert_eq!(
+        m.xattr("/a.txt", "user.recfs.hash"),
+        format!("{:x}", md5::compute(b"hello"))
+    );
+    assert_eq!(m.xattr("/a.txt", "user.recfs.disk"), "cloud");
+    assert_eq!(m.xattr("/?Recycle/b.txt", "user.recfs.disk"), "recycle");
+    assert_eq!(m.xattr("/a.txt", "user.recfs.cached"), "0");
+    let url = m.xattr("/a.txt", "user.recfs.download_url");
+    assert!(url.contains(&a));
+    assert_eq!(m.read("/a.txt"), b"hello");
+    assert_eq!(m.xattr("/a.txt", "user.recfs.cached"), "1");
+
+    // only local attributes for files which are not uploaded yet
+    m.fs.create(
+        req(),
+        Path::new("/"),
+        OsStr::new("new.txt"),
+        0o600,
+        libc::O_WRONLY as u32,
+    )
+    .unwrap();
+    let names = match m.fs.listxattr(req(), Path::new("/new.txt"), 256) {
+        Ok(Xattr::Data(data)) => data,
+        res => panic!("listxattr: {:?}", res.err()),
+    };
+    assert_eq!(names, b"user.recfs.pin\0user.recfs.cached\0");
+    let fid = m.fs.getxattr(
+        req(),
+        Path::new("/new.txt"),
+        OsStr::new("user.recfs.fid"),
+        0,
+    );
+    assert_eq!(fid.err(), Some(libc::ENODATA));
+}
+
+#[test]
+fn test_hash_check() {
+    let m = Mounted::new();
+    let a = m.mock.add_file("0", "a.txt", b"hello");
+
+    // a download which does not match the listing is not kept
+    m.mock.set_hash(&a, &"0".repeat(32));
+    let path = Path::new("/a.txt");
+    let (fh, _) = m.fs.open(req(), path, libc::O_RDONLY as u32).unwrap();
+    assert_eq!(m.fs.read_data(fh, 0, 100), Err(libc::EIO));
+    m.fs.release(req(), path, fh, 0, 0, true).unwrap();
+    assert!(!m.dir.join("cache").join(&a).exists());
+    m.mock
+        .set_hash(&a, &format!("{:x}", md5::compute(b"hello")));
+    m.fs.poll();
+    assert_eq!(m.read("/a.txt"), b"hello");
+
+    // an upload which does not match the local file is recycled
+    m.mock.corrupt_uploads(true);
+    m.write_new("/", "b.txt", b"world").unwrap();
+    assert_eq!(m.mock.find("0", "b.txt"), None);
+    let status = m.xattr("/", "user.recfs.uploads");
+    assert!(status.contains("b.txt: content hash"), "{}", status);
+
+    // so is a write back, keeping the old file
+    let (fh, _) = m.fs.open(req(), path, libc::O_RDWR as u32).unwrap();
+    m.fs.write(req(), path, fh, 0, b"J".to_vec(), 0).unwrap();
+    assert_eq!(
+        m.fs.release(req(), path, fh, libc::O_RDWR as u32, 0, true),
+        Err(libc::EIO)
+    );
+    assert_eq!(m.mock.find("0", "a.txt"), Some(a.clone()));
+    assert_eq!(m.mock.content(&a).unwrap(), b"hello");
+    // the failed new file is still there, to be uploaded again
+    assert_eq!(
+        m.readdir("/"),
+        vec!["?Backup", "?Recycle", "a.txt", "b.txt"]
+    );
+
+    // a file of the same name put by someone else during the upload is left alone
+    m.mock.hold_uploads(true);
+    let created =
+        m.fs.create(
+            req(),
+            Path::new("/"),
+            OsStr::new("c.txt"),
+            0o600,
+            libc::O_WRONLY as u32,
+        )
+        .unwrap();
+    let path = Path::new("/c.txt");
+    m.fs.write(req(), path, created.fh, 0, b"mine".to_vec(), 0)
+        .unwrap();
+    m.fs.release(req(), path, created.fh, 0, 0, true).unwrap();
+    while m.mock.held() == 0 {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let other = m.mock.add_file("0", "c.txt", b"theirs");
+    m.mock.hold_uploads(false);
+    m.fs.wait_uploads();
+    assert_eq!(m.mock.disk(&other).unwrap(), "cloud");
+    assert_eq!(m.mock.content(&other).unwrap(), b"theirs");
+    // while the corrupted copy of this one, renamed by rec, is recycled
+    assert_eq!(m.mock.find("0", "c(1).txt"), None);
+}
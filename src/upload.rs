@@ -0,0 +1,401 @@
+// Uploads of created files in background, so that release() does not wait for the network
+use crate::cache::{self, Cache, UploadState};
+use crate::client::error::RecError;
+use crate::client::list::RecListItem;
+use crate::client::operation::Operation;
+use crate::client::RecClient;
+use crate::fid::Fid;
+use crate::fidmap::FidMap;
+use fuse_mt::FileType;
+use log::{error, info, warn};
+use std::collections::{HashSet, VecDeque};
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::SystemTime;
+
+// failed uploads kept for status()
+const MAX_FAILURES: usize = 100;
+// times an upload is resumed after it failed with a transient error
+const RESUMES: u32 = 2;
+
+pub struct UploadQueue {
+    state: Arc<(Mutex<QueueState>, Condvar)>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+    client: Arc<RecClient>,
+    fid_map: Arc<RwLock<FidMap>>,
+    cache: Arc<Cache>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    queued: VecDeque<Fid>,
+    uploading: HashSet<Fid>,
+    uploaded: u64,
+    failed: VecDeque<Failure>,
+    stop: bool,
+}
+
+// what a worker has done with a queued file
+enum Outcome {
+    Uploaded,
+    // nothing to upload now, e.g. it is open again
+    Skipped,
+    // changed while it was being uploaded: to be uploaded again
+    Changed,
+}
+
+struct Failure {
+    fid: Fid,
+    name: String,
+    error: String,
+}
+
+impl UploadQueue {
+    pub fn start(
+        workers: usize,
+        client: Arc<RecClient>,
+        fid_map: Arc<RwLock<FidMap>>,
+        cache: Arc<Cache>,
+    ) -> Self {
+        let state = Arc::new((Mutex::new(QueueState::default()), Condvar::new()));
+        let workers = (0..workers.max(1))
+            .map(|_| {
+                let state = state.clone();
+                let client = client.clone();
+                let fid_map = fid_map.clone();
+                let cache = cache.clone();
+                std::thread::spawn(move || work(&state, &client, &fid_map, &cache))
+            })
+            .collect();
+        Self {
+            state,
+            workers: Mutex::new(workers),
+            client,
+            fid_map,
+            cache,
+        }
+    }
+
+    pub fn push(&self, fid: Fid) {
+        self.cache.set_upload_state(fid, UploadState::Queued);
+        let (lock, cvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        if !state.queued.contains(&fid) {
+            state.queued.push_back(fid);
+            cvar.notify_all();
+        }
+    }
+
+    // upload a created file which is still open, for fsync()
+    // it waits for an upload of fid by a worker, and keeps the workers off fid meanwhile
+    // Err with the errno of the error if the upload fails
+    pub fn upload_open(&self, fid: Fid) -> Result<(), libc::c_int> {
+        let (lock, cvar) = &*self.state;
+        {
+            let mut state = cvar
+                .wait_while(lock.lock().unwrap(), |s| s.uploading.contains(&fid))
+                .unwrap();
+            state.queued.retain(|f| *f != fid);
+            state.uploading.insert(fid);
+        }
+        let name = self.fid_map.read().unwrap().get_item(&fid).map(|i| i.name);
+        let res = upload_open(&self.client, &self.fid_map, &self.cache, fid);
+        let errno = res.as_ref().err().map(|e| e.errno());
+        let mut state = lock.lock().unwrap();
+        state.uploading.remove(&fid);
+        record(&mut state, fid, name, res);
+        cvar.notify_all();
+        errno.map_or(Ok(()), Err)
+    }
+
+    // a created file is unlinked: it is not to be uploaded, nor to be reported as failed
+    pub fn forget(&self, fid: Fid) {
+        let mut state = self.state.0.lock().unwrap();
+        state.queued.retain(|f| *f != fid);
+        state.failed.retain(|f| f.fid != fid);
+    }
+
+    // wait until every queued upload is done
+    #[cfg(test)]
+    pub fn wait_all(&self) {
+        let (lock, cvar) = &*self.state;
+        let _state = cvar
+            .wait_while(lock.lock().unwrap(), |s| {
+                !s.queued.is_empty() || !s.uploading.is_empty()
+            })
+            .unwrap();
+    }
+
+    // finish queued uploads, then stop the workers
+    pub fn stop(&self) {
+        {
+            let (lock, cvar) = &*self.state;
+            lock.lock().unwrap().stop = true;
+            cvar.notify_all();
+        }
+        for worker in self.workers.lock().unwrap().drain(..) {
+            let _ = worker.join();
+        }
+    }
+
+    // a human readable summary, one item per line
+    pub fn status(&self) -> String {
+        let state = self.state.0.lock().unwrap();
+        let mut status = format!(
+            "queued: {}\nuploading: {}\nuploaded: {}\nfailed: {}\n",
+            state.queued.len(),
+            state.uploading.len(),
+            state.uploaded,
+            state.failed.len()
+        );
+        for failure in state.failed.iter() {
+            let _ = writeln!(status, "{}: {}", failure.name, failure.error);
+        }
+        status
+    }
+}
+
+fn work(
+    state: &(Mutex<QueueState>, Condvar),
+    client: &RecClient,
+    fid_map: &RwLock<FidMap>,
+    cache: &Cache,
+) {
+    let (lock, cvar) = state;
+    loop {
+        let fid = {
+            let mut state = lock.lock().unwrap();
+            loop {
+                // a file queued again while it is being uploaded waits for that upload
+                let next = state
+                    .queued
+                    .iter()
+                    .position(|fid| !state.uploading.contains(fid));
+                if let Some(fid) = next.and_then(|i| state.queued.remove(i)) {
+                    state.uploading.insert(fid);
+                    break fid;
+                }
+                if state.stop {
+                    return;
+                }
+                state = cvar.wait(state).unwrap();
+            }
+        };
+        let name = fid_map.read().unwrap().get_item(&fid).map(|i| i.name);
+        let res = upload(client, fid_map, cache, fid);
+        let mut state = lock.lock().unwrap();
+        state.uploading.remove(&fid);
+        record(&mut state, fid, name, res);
+        cvar.notify_all();
+    }
+}
+
+// count what has been done with fid, and keep its failure for status()
+fn record(state: &mut QueueState, fid: Fid, name: Option<String>, res: Result<Outcome, RecError>) {
+    match res {
+        Ok(Outcome::Uploaded) => {
+            state.uploaded += 1;
+            state.failed.retain(|f| f.fid != fid);
+        }
+        Ok(Outcome::Skipped) => {}
+        Ok(Outcome::Changed) => {
+            if !state.queued.contains(&fid) {
+                state.queued.push_back(fid);
+            }
+        }
+        Err(e) => {
+            let name = name.unwrap_or_else(|| fid.to_string());
+            // most programs ignore the return value of close()
+            // so here warn! to notify users of uploading failure
+            warn!("Upload of {} failed: {}", name, e);
+            state.failed.retain(|f| f.fid != fid);
+            if state.failed.len() >= MAX_FAILURES {
+                state.failed.pop_front();
+            }
+            state.failed.push_back(Failure {
+                fid,
+                name,
+                error: e.to_string(),
+            });
+        }
+    }
+}
+
+// upload a created file, after which rec's listing replaces the pending entry
+fn upload(
+    client: &RecClient,
+    fid_map: &RwLock<FidMap>,
+    cache: &Cache,
+    fid: Fid,
+) -> Result<Outcome, RecError> {
+    // opened again: uploaded when the last handle is closed
+    if fid_map.read().unwrap().has_handles(&fid) {
+        cache.set_upload_state(fid, UploadState::Writing);
+        return Ok(Outcome::Skipped);
+    }
+    let (parent, filename) = match cache.created_info(fid) {
+        Some(info) => info,
+        // unlinked, or uploaded by fsync()
+        None => return Ok(Outcome::Skipped),
+    };
+    cache.set_upload_state(fid, UploadState::Uploading);
+    let filepath = cache.get_created_path(fid);
+    let before = stamp(&filepath);
+    let res = send(client, fid_map, cache, fid, parent, &filename, &filepath);
+
+    // written meanwhile: what rec has got (if anything) is stale already
+    let writing = fid_map.read().unwrap().has_writers(&fid);
+    if writing || stamp(&filepath) != before {
+        info!("{} has changed while being uploaded", filename);
+        if let Ok(item) = &res {
+            recycle(client, fid_map, parent, item.fid, &item.name);
+        }
+        if writing {
+            cache.set_upload_state(fid, UploadState::Writing);
+            return Ok(Outcome::Skipped);
+        }
+        cache.set_upload_state(fid, UploadState::Queued);
+        return Ok(Outcome::Changed);
+    }
+    match res {
+        Ok(item) => {
+            uploaded(fid_map, cache, fid, &item);
+            Ok(Outcome::Uploaded)
+        }
+        // kept in the journal, so that it is tried again by the next mount
+        Err(e) => {
+            cache.set_upload_state(fid, UploadState::Failed);
+            Err(e)
+        }
+    }
+}
+
+// upload a created file although it is open, then it is a file on rec
+fn upload_open(
+    client: &RecClient,
+    fid_map: &RwLock<FidMap>,
+    cache: &Cache,
+    fid: Fid,
+) -> Result<Outcome, RecError> {
+    let (parent, filename) = match cache.created_info(fid) {
+        Some(info) => info,
+        // unlinked, or uploaded already
+        None => return Ok(Outcome::Skipped),
+    };
+    cache.set_upload_state(fid, UploadState::Uploading);
+    let filepath = cache.get_created_path(fid);
+    let res = send(client, fid_map, cache, fid, parent, &filename, &filepath);
+    // uploaded again on release otherwise
+    cache.set_upload_state(fid, UploadState::Writing);
+    uploaded(fid_map, cache, fid, &res?);
+    Ok(Outcome::Uploaded)
+}
+
+// size and modification time of a local file, which change with its content
+fn stamp(path: &Path) -> Option<(u64, SystemTime)> {
+    let metadata = path.metadata().ok()?;
+    Some((metadata.len(), metadata.modified().ok()?))
+}
+
+// upload the content of a created file and check what rec has got
+// the new item on rec, as found in the listing
+fn send(
+    client: &RecClient,
+    fid_map: &RwLock<FidMap>,
+    cache: &Cache,
+    fid: Fid,
+    parent: Fid,
+    filename: &str,
+    filepath: &Path,
+) -> Result<RecListItem, RecError> {
+    if let Some(session) = cache.upload_session(fid) {
+        if let Some(number) = session.completed() {
+            // uploaded by an earlier try, which failed to list it
+            if session.matches(filepath) {
+                info!("Check the upload of {} ({}) again", filename, fid);
+                return verify(client, fid_map, cache, fid, parent, filepath, number);
+            }
+            // which has changed since
+            recycle(client, fid_map, parent, number, filename);
+            cache.forget_upload_session(fid);
+        }
+    }
+    info!("Upload {} ({})", filename, fid);
+    let mut resumes = 0;
+    let number = loop {
+        let session = cache.upload_session(fid);
+        let res = client.upload_resumable(parent, filepath, filename.to_owned(), session, |s| {
+            cache.set_upload_session(fid, s)
+        });
+        match res {
+            // a network blip: continue from the chunks uploaded so far
+            Err(e) if resumes < RESUMES && (e.is_unreachable() || e.is_retryable(true)) => {
+                resumes += 1;
+                warn!("Upload of {} failed: {}, resuming", filename, e);
+                std::thread::sleep(client.config().retry.max_delay);
+            }
+            res => break res?,
+        }
+    };
+    verify(client, fid_map, cache, fid, parent, filepath, number)
+}
+
+// the pending entry and the local copy make way for the uploaded file
+// open handles go on with it
+fn uploaded(fid_map: &RwLock<FidMap>, cache: &Cache, fid: Fid, item: &RecListItem) {
+    cache.uploaded(fid, item);
+    fid_map.write().unwrap().replace_pending(&fid, &item.fid);
+}
+
+// compare the file rec has got with the local one, and recycle it if they differ
+// number is what rec has returned for the upload, so only a file made by it is recycled
+// if the listing fails, the upload is kept in the journal, so that only the listing is
+// tried again
+fn verify(
+    client: &RecClient,
+    fid_map: &RwLock<FidMap>,
+    cache: &Cache,
+    fid: Fid,
+    parent: Fid,
+    path: &Path,
+    number: Fid,
+) -> Result<RecListItem, RecError> {
+    let items = client.list(parent).map_err(|e| {
+        warn!("Failed to list {} after uploading {}: {}", parent, fid, e);
+        e
+    })?;
+    // found by its number, as others may upload files of the same name meanwhile
+    let uploaded = items.iter().find(|i| i.fid == number).cloned();
+    fid_map.write().unwrap().set_children(parent, items);
+    let uploaded = match uploaded {
+        Some(item) => item,
+        // gone from rec already: to be uploaded again
+        None => {
+            cache.forget_upload_session(fid);
+            return Err(RecError::Invalid(format!(
+                "{} is not listed by rec after uploading",
+                number
+            )));
+        }
+    };
+    if let Err(e) = cache::check_hash(path, uploaded.hash.as_deref()) {
+        error!("Upload of {} is corrupted on rec: {}", uploaded.name, e);
+        recycle(client, fid_map, parent, uploaded.fid, &uploaded.name);
+        cache.forget_upload_session(fid);
+        return Err(e);
+    }
+    Ok(uploaded)
+}
+
+// move an uploaded file which is not to be kept to the recycle bin
+fn recycle(client: &RecClient, fid_map: &RwLock<FidMap>, parent: Fid, fid: Fid, name: &str) {
+    let deleted = client.operation(Operation::Delete, fid, FileType::RegularFile, None);
+    if let Err(e) = deleted {
+        warn!("Failed to recycle {}: {}", name, e);
+    }
+    if let Ok(items) = client.list(parent) {
+        fid_map.write().unwrap().set_children(parent, items);
+    }
+}